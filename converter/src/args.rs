@@ -0,0 +1,107 @@
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use converter::Encoding;
+use std::path::PathBuf;
+
+// コマンドライン引数．
+// 何も指定しなければ，リポジトリのルートで
+// source を読んで index.html に書き出す（今までと同じ動作）．
+#[derive(Parser)]
+#[command(version, about = "source ディレクトリの内容を HTML に変換する")]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub options: Options,
+    /// 入力のファイルが変更されるたびに変換し直す
+    #[arg(short, long)]
+    pub watch: bool,
+    /// 読み込むファイルを連結する順に並べ，読まないファイルとその理由も表示する．変換はしない
+    #[arg(long, conflicts_with = "watch")]
+    pub list_files: bool,
+}
+
+impl Args {
+    /// 引数を読む． --watch と --list-files はサブコマンドと一緒には使えない
+    pub fn parse_checked() -> Args {
+        let args = Args::parse();
+        if let Some(command) = &args.command {
+            let flag = if args.watch {
+                "--watch"
+            } else if args.list_files {
+                "--list-files"
+            } else {
+                return args;
+            };
+            let name = match command {
+                Command::Serve { .. } => "serve",
+                Command::Check { .. } => "check",
+            };
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("the argument '{}' cannot be used with '{}'", flag, name),
+                )
+                .exit();
+        }
+        args
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// ローカルに HTTP サーバーを立て，変換結果をプレビューする
+    ///
+    /// 入力のファイルが変更されるたびに変換し直し，ブラウザを再読み込みさせる．
+    Serve {
+        /// 待ち受けるアドレス
+        #[arg(short, long, default_value = "127.0.0.1:8000")]
+        address: String,
+    },
+    /// 変換して誤りが無いかを調べる．出力は書き出さない
+    ///
+    /// エラーがあれば（ --deny-warnings のときは警告があっても）終了ステータス 1 で終了する．
+    Check {
+        /// 警告もエラーとみなす
+        #[arg(long)]
+        deny_warnings: bool,
+    },
+}
+
+// 変換の仕方についての引数（サブコマンドで共通）．
+// サブコマンドの前後どちらに書いてもよい
+#[derive(clap::Args)]
+pub struct Options {
+    /// 入力のディレクトリ
+    #[arg(default_value = "source", global = true)]
+    pub source: PathBuf,
+    /// 出力先のファイル．ディレクトリを指定すると，その中の index.html に書き出す
+    #[arg(short, long, default_value = "index.html", global = true)]
+    pub output: PathBuf,
+    /// HTML から参照するスタイルシートのパス
+    #[arg(short, long, default_value = "style.css", global = true)]
+    pub stylesheet: String,
+    /// ページのタイトル
+    #[arg(short, long, default_value = "化合物から見る代謝経路", global = true)]
+    pub title: String,
+    /// エラーと警告以外を出力しない
+    #[arg(short, long, conflicts_with = "verbose", global = true)]
+    pub quiet: bool,
+    /// 読み込んだファイルなど，途中経過も出力する
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+    /// source のファイルの文字コード（ utf-8, shift_jis, euc-jp ）
+    #[arg(long, default_value = "utf-8", global = true)]
+    pub encoding: Encoding,
+}
+
+impl Options {
+    // 実際に書き出すファイルのパス
+    pub fn output_file(&self) -> PathBuf {
+        if self.output.is_dir() {
+            self.output.join("index.html")
+        } else {
+            self.output.clone()
+        }
+    }
+}
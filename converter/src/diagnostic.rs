@@ -0,0 +1,241 @@
+use super::source_map::{SourceMap, Span};
+use std::error::Error;
+use unicode_width::UnicodeWidthChar;
+
+/// エラーか警告か．エラーがあると出力は書き出されない．
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+/// エラーや警告 1 件分．
+/// メッセージと，ソース中の関係する箇所（ラベル）をもつ．
+pub struct Diagnostic {
+    message: String,
+    labels: Vec<(Span, String)>,
+    helps: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new<M: ToString>(message: M) -> Diagnostic {
+        Diagnostic {
+            message: message.to_string(),
+            labels: Vec::new(),
+            helps: Vec::new(),
+        }
+    }
+    /// 最初に付けたラベルの位置が，メッセージの位置として表示される
+    pub fn label<L: Into<String>>(mut self, span: Span, label: L) -> Diagnostic {
+        self.labels.push((span, label.into()));
+        self
+    }
+    /// 直し方の提案など，ソースの抜粋の後に付け加える一言
+    pub fn help<H: Into<String>>(mut self, help: H) -> Diagnostic {
+        self.helps.push(help.into());
+        self
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+    pub fn labels(&self) -> &[(Span, String)] {
+        &self.labels
+    }
+    pub fn helps(&self) -> &[String] {
+        &self.helps
+    }
+    /// メッセージ，ソースの抜粋，提案を並べた文字列にする．
+    /// 該当する行は source_map から取ってくる．
+    pub fn render(&self, level: Level, source_map: &SourceMap) -> String {
+        let mut ret = match level {
+            Level::Error => format!("error: {}\n", self.message),
+            Level::Warning => format!("warning: {}\n", self.message),
+        };
+        ret += &snippet(&self.labels, source_map);
+        for help in &self.helps {
+            ret += &format!(
+                "{} = help: {}\n",
+                " ".repeat(gutter_width(&self.labels, source_map)),
+                help
+            );
+        }
+        ret
+    }
+}
+
+// 位置の情報をもたないエラー（ファイルが開けないなど）
+impl From<Box<dyn Error>> for Diagnostic {
+    fn from(err: Box<dyn Error>) -> Diagnostic {
+        Diagnostic::new(err)
+    }
+}
+
+impl From<String> for Diagnostic {
+    fn from(message: String) -> Diagnostic {
+        Diagnostic::new(message)
+    }
+}
+
+/// パース中，コンパイル中，出力中に見つかったエラーや警告を溜めておき，
+/// 最後にまとめて出力する．
+/// 1 つ目のエラーで止まってしまうと，
+/// 間違いを 1 つ直すたびに実行し直さなければならないため．
+#[derive(Default)]
+pub struct Diagnostics {
+    // 見つかった順に並べておく
+    entries: Vec<(Level, Diagnostic)>,
+}
+
+impl Diagnostics {
+    pub fn error<D: Into<Diagnostic>>(&mut self, err: D) {
+        self.entries.push((Level::Error, err.into()));
+    }
+    pub fn warning<D: Into<Diagnostic>>(&mut self, warning: D) {
+        self.entries.push((Level::Warning, warning.into()));
+    }
+    pub fn error_count(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|(level, _)| *level == Level::Error)
+            .count()
+    }
+    pub fn warning_count(&self) -> usize {
+        self.entries.len() - self.error_count()
+    }
+    pub fn has_errors(&self) -> bool {
+        self.error_count() > 0
+    }
+    /// 見つかった順に取り出す
+    pub fn iter(&self) -> impl Iterator<Item = (Level, &Diagnostic)> {
+        self.entries
+            .iter()
+            .map(|(level, diagnostic)| (*level, diagnostic))
+    }
+    /// 溜まっているものを全て並べて，最後に件数を付けた文字列にする．
+    pub fn render(&self, source_map: &SourceMap) -> String {
+        let mut ret = String::new();
+        for (level, diagnostic) in self.iter() {
+            ret += &diagnostic.render(level, source_map);
+            ret += "\n";
+        }
+        if !self.entries.is_empty() {
+            let errors = self.error_count();
+            let warnings = self.warning_count();
+            ret += &format!(
+                "{} error{}, {} warning{}\n",
+                errors,
+                if errors == 1 { "" } else { "s" },
+                warnings,
+                if warnings == 1 { "" } else { "s" },
+            );
+        }
+        ret
+    }
+    /// 標準エラー出力に書き出す
+    pub fn print(&self, source_map: &SourceMap) {
+        eprint!("{}", self.render(source_map));
+    }
+}
+
+// ラベルの付いた行を抜き出して，
+//
+//  --> source/11_糖代謝:5:9
+//   |
+// 5 |     glucose [グルコース
+//   |             ^ not closed
+//
+// のような形にする．
+fn snippet(labels: &[(Span, String)], source_map: &SourceMap) -> String {
+    let mut ret = String::new();
+    if labels.is_empty() {
+        return ret;
+    }
+    let gutter = gutter_width(labels, source_map);
+    let blank = " ".repeat(gutter);
+
+    // ファイルごとにまとめ，ファイルの中では行の順に並べる
+    let mut files = Vec::<(usize, Vec<&(Span, String)>)>::new();
+    for label in labels {
+        match files.iter_mut().find(|(file, _)| *file == label.0.file) {
+            Some((_, group)) => group.push(label),
+            None => files.push((label.0.file, vec![label])),
+        }
+    }
+    for (i, (file, group)) in files.iter_mut().enumerate() {
+        let file = source_map.file(*file);
+        let (first, _) = group[0];
+        let (first_line, first_pos) = file.position(first.start);
+        ret += &format!(
+            "{}{} {}:{}:{}\n",
+            blank,
+            if i == 0 { "-->" } else { ":::" },
+            file.path.display(),
+            first_line,
+            first_pos
+        );
+        ret += &format!("{} |\n", blank);
+        group.sort_by_key(|(span, _)| span.start);
+        let mut prev_line = None;
+        for (span, label) in group.iter() {
+            let (line_number, pos) = file.position(span.start);
+            let (end_line, end_pos) = file.position(span.end);
+            if prev_line != Some(line_number) {
+                if let Some(prev_line) = prev_line {
+                    if line_number > prev_line + 1 {
+                        ret += "...\n";
+                    }
+                }
+                let line = file.line(line_number);
+                ret += &format!("{:>gutter$} | {}\n", line_number, expand_tabs(line));
+                prev_line = Some(line_number);
+            }
+            let line = file.line(line_number);
+            // end_pos は範囲の直後の文字
+            let end_pos = if end_line == line_number {
+                end_pos
+            } else {
+                // 複数行にまたがるときは，最初の行の終わりまで
+                line.chars().count() + 1
+            };
+            let indent = width(line.chars().take(pos - 1));
+            let underline = width(line.chars().skip(pos - 1).take(end_pos - pos)).max(1);
+            ret += &format!(
+                "{} | {}{} {}\n",
+                blank,
+                " ".repeat(indent),
+                "^".repeat(underline),
+                label
+            );
+        }
+    }
+    ret
+}
+
+// 行番号を書く欄の幅
+fn gutter_width(labels: &[(Span, String)], source_map: &SourceMap) -> usize {
+    labels
+        .iter()
+        .map(|(span, _)| {
+            let (line, _) = source_map.file(span.file).position(span.start);
+            line.to_string().len()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+// タブはこの幅の空白に置き換えて表示する
+const TAB_WIDTH: usize = 4;
+
+fn expand_tabs(line: &str) -> String {
+    line.replace('\t', &" ".repeat(TAB_WIDTH))
+}
+
+// 端末に表示したときの幅（全角文字は 2 ）
+fn width<I: Iterator<Item = char>>(chars: I) -> usize {
+    chars
+        .map(|c| match c {
+            '\t' => TAB_WIDTH,
+            c => c.width().unwrap_or(0),
+        })
+        .sum()
+}
@@ -1,104 +1,356 @@
-use super::char::{Char, Display};
-use super::source::Expr;
-use super::text::Text;
+use super::diagnostic::{Diagnostic, Diagnostics};
+use super::escape::{Attr, Html};
+use super::formula::Formula;
+use super::metadata::{Field, FieldKind, Value};
+use super::normalize::normalize;
+use super::reaction::{self, Reaction};
+use super::source::{Expr, Word};
+use super::source_map::Span;
+use super::suggest::suggest;
+use super::text::{Text, Token};
+use serde::{Deserialize, Serialize};
 use std::collections::{BTreeSet, HashMap};
 use std::error::Error;
 
-pub struct Item<'a, 'b> {
-    pub identity: &'a [Char],
-    pub name: Option<&'b Text<'a>>,
-    pub descs: Vec<&'b Text<'a>>,
+/// 1 つの ID について集めた名前，説明，分類，情報．
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Item {
+    pub identity: Word,
+    pub name: Option<Text>,
+    pub descs: Vec<Text>,
     pub groups: BTreeSet<usize>,
+    // \formula{ } などで書かれた情報．書かれた順に並ぶ
+    pub fields: Vec<Field>,
+    // \formula{ } を読んだ結果．読めなかったときは None
+    pub formula: Option<Formula>,
+    // この化合物が関わる反応． Document の reactions の何番目か
+    pub reactions: Vec<usize>,
 }
 
-impl<'a, 'b> Item<'a, 'b> {
-    fn from_identity(s: &'a [Char]) -> Item<'a, 'b> {
+impl Item {
+    fn from_identity(s: Word) -> Item {
         Item {
             identity: s,
             name: None,
             descs: Vec::new(),
             groups: BTreeSet::new(),
+            fields: Vec::new(),
+            formula: None,
+            reactions: Vec::new(),
         }
     }
+
+    /// \charge{ } で書かれた電荷
+    pub fn declared_charge(&self) -> Option<i64> {
+        self.fields
+            .iter()
+            .find_map(|field| match (field.kind, &field.value) {
+                (FieldKind::Charge, Value::Integer(charge)) => Some(*charge),
+                _ => None,
+            })
+    }
+
+    // 分子式に書かれた電荷と \charge{ } が食い違っていないか．
+    // 分子式に電荷が書かれていなければ比べない
+    fn check_charge(&self, diagnostics: &mut Diagnostics) {
+        let (formula, declared) = match (&self.formula, self.declared_charge()) {
+            (Some(formula), Some(declared)) if formula.charge != 0 => (formula, declared),
+            _ => return,
+        };
+        if formula.charge != declared {
+            let span = |kind| {
+                self.fields
+                    .iter()
+                    .find(|field| field.kind == kind)
+                    .unwrap()
+                    .span
+            };
+            diagnostics.error(CompileError::ChargeMismatch(
+                self.identity.value.clone(),
+                formula.charge,
+                declared,
+                span(FieldKind::Formula),
+                span(FieldKind::Charge),
+            ));
+        }
+    }
+}
+
+/// source のサブディレクトリ 1 つ分の章．
+/// 中身は，ID が初めて現れた順と，サブディレクトリの順に並ぶ．
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Chapter {
+    pub title: String,
+    pub contents: Vec<Content>,
 }
 
-#[derive(Default)]
-pub struct Document<'a, 'b> {
-    pub headers: Vec<(&'a [Char], &'b Text<'a>)>,
-    pub items: Vec<Item<'a, 'b>>,
-    pub groups: Vec<&'a [Char]>,
-    pub names: HashMap<&'b Text<'a>, usize>,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Content {
+    Item(usize), // Document の items の何番目か
+    Chapter(Chapter),
+}
+
+/// source 全体を ID ごとにまとめたもの．
+/// Expr の列から中身を移して作るので，読み込んだ文字列とは独立に持ち回せる．
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Document {
+    pub headers: Vec<(Word, Text)>,
+    pub items: Vec<Item>,
+    // 章立て．一番外側は source ディレクトリそのもので，見出しをもたない．
+    // 各 item は，その ID が初めて現れた章に置かれる
+    pub root: Chapter,
+    pub groups: Vec<Word>,
+    // \reaction{ } で書かれた反応．書かれた順に並ぶ
+    pub reactions: Vec<Reaction>,
+    // source に書かれたままの形の名前から引く
+    pub names: HashMap<String, usize>,
+    // 正規化した名前から引く（ normalize.rs ）
+    pub normalized_names: HashMap<String, usize>,
+}
+
+/// index.html の `<head>` などに書く，source の外から与えられる情報
+pub struct Page<'c> {
+    pub title: &'c str,
+    pub stylesheet: &'c str,
 }
 
 #[derive(thiserror::Error, Debug)]
 enum CompileError {
     #[error("identity expected before name")]
-    NoIdentityBeforeName,
+    NoIdentityBeforeName(Span),
     #[error("identity expected before description")]
-    NoIdentityBeforeDesc,
-    #[error("duplicate name")]
-    DuplicateName,
+    NoIdentityBeforeDesc(Span),
+    #[error("duplicate name for `{0}`")]
+    DuplicateName(String, Span, Span), // 最初に付けた名前と，食い違う名前
+    #[error("`{0}` is not a valid tag name")]
+    InvalidTagName(String, Span),
+    #[error("`\\{0}` must follow an identity")]
+    NoIdentityBeforeField(&'static str, Span),
+    #[error("conflicting `\\{0}` for `{1}`")]
+    DuplicateField(&'static str, String, Span, Span), // 最初に書いた値と，食い違う値
+    #[error("name `{0}` is used for both `{1}` and `{2}`")]
+    NameConflict(String, String, String, Span, Span), // 先に付けた名前と，同じ名前
+    #[error("`\\charge` of `{0}` does not match its formula")]
+    ChargeMismatch(String, i64, i64, Span, Span), // 分子式と \charge{ } の電荷，それぞれの位置
+}
+
+#[derive(thiserror::Error, Debug)]
+enum CompileWarning {
+    #[error("names `{0}` and `{1}` are the same after normalization")]
+    SimilarNames(String, String, Span, Span), // 先に付けた名前と，紛らわしい名前
 }
 
 #[derive(thiserror::Error, Debug)]
 enum DocumentPrintError {
-    #[error("no name")]
-    NoName,
+    #[error("name of `{0}` not provided")]
+    NoName(String, Span),
+}
+
+impl From<CompileError> for Diagnostic {
+    fn from(err: CompileError) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&err);
+        match err {
+            CompileError::NoIdentityBeforeName(span) => {
+                diagnostic.label(span, "this name belongs to no identity")
+            }
+            CompileError::NoIdentityBeforeDesc(span) => {
+                diagnostic.label(span, "this description belongs to no identity")
+            }
+            CompileError::DuplicateName(_, first, second) => diagnostic
+                .label(second, "conflicting name")
+                .label(first, "first named here"),
+            CompileError::InvalidTagName(_, span) => {
+                diagnostic.label(span, "only alphanumeric characters are allowed")
+            }
+            CompileError::NoIdentityBeforeField(_, span) => {
+                diagnostic.label(span, "this field belongs to no identity")
+            }
+            CompileError::DuplicateField(_, _, first, second) => diagnostic
+                .label(second, "conflicting value")
+                .label(first, "first given here"),
+            CompileError::NameConflict(_, first_id, _, first, second) => diagnostic
+                .label(second, "used again here")
+                .label(first, format!("first used for `{}`", first_id))
+                .help("give one of them a different name; links refer to the first one"),
+            CompileError::ChargeMismatch(_, formula, declared, formula_span, charge_span) => {
+                diagnostic
+                    .label(charge_span, format!("charge is {} here", declared))
+                    .label(
+                        formula_span,
+                        format!("but the formula has charge {}", formula),
+                    )
+            }
+        }
+    }
+}
+
+impl From<CompileWarning> for Diagnostic {
+    fn from(warning: CompileWarning) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&warning);
+        match warning {
+            CompileWarning::SimilarNames(first_name, _, first, second) => diagnostic
+                .label(second, "similar name here")
+                .label(first, "first used here")
+                .help(format!(
+                    "links differing only in spacing or character width refer to `{}`",
+                    first_name
+                )),
+        }
+    }
+}
+
+impl From<DocumentPrintError> for Diagnostic {
+    fn from(err: DocumentPrintError) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&err);
+        match err {
+            DocumentPrintError::NoName(_, span) => {
+                diagnostic.label(span, "write `[name]` after this")
+            }
+        }
+    }
 }
 
-impl<'a, 'b> Document<'a, 'b> {
-    pub fn from_source(source: &'b Vec<Expr<'a>>) -> Result<Document<'a, 'b>, Box<dyn Error>> {
+impl Document {
+    /// 誤りがあった要素は飛ばして，残りから Document を作る．
+    pub fn from_source(source: Vec<Expr>, diagnostics: &mut Diagnostics) -> Document {
         let mut ret: Document = Default::default();
-        let mut groups = HashMap::<&[Char], usize>::new();
-        let mut identities = HashMap::<&[Char], usize>::new();
+        let mut groups = HashMap::<String, usize>::new();
+        let mut identities = HashMap::<String, usize>::new();
         let mut index = None;
+        // いま開いている章．先頭は root
+        let mut chapters = vec![Chapter::default()];
         for expr in source {
             match expr {
-                Expr::Identity(identity) => match identities.get(identity) {
+                Expr::Identity(identity) => match identities.get(&identity.value) {
                     Some(value) => {
                         index = Some(*value);
                     }
                     None => {
                         let len = identities.len();
-                        identities.insert(identity, len);
+                        identities.insert(identity.value.clone(), len);
                         ret.items.push(Item::from_identity(identity));
+                        chapters
+                            .last_mut()
+                            .unwrap()
+                            .contents
+                            .push(Content::Item(len));
                         index = Some(len);
                     }
                 },
                 Expr::Name(name) => match index {
                     Some(index) => match &ret.items[index].name {
                         Some(prev) => {
-                            if name != *prev {
-                                eprint!(
-                                    "error: duplicate name for `{}`",
-                                    Display::from(ret.items[index].identity)
-                                );
-                                return Err(Box::new(CompileError::DuplicateName));
+                            if name.to_string() != prev.to_string() {
+                                diagnostics.error(CompileError::DuplicateName(
+                                    ret.items[index].identity.value.clone(),
+                                    prev.span,
+                                    name.span,
+                                ));
                             }
                         }
                         None => {
+                            let key = name.to_string();
+                            // 別の ID に同じ名前が付いていたら，先に付けた方をリンク先として残す
+                            match ret.names.get(&key) {
+                                Some(&prev) => {
+                                    let prev = &ret.items[prev];
+                                    diagnostics.error(CompileError::NameConflict(
+                                        key,
+                                        prev.identity.value.clone(),
+                                        ret.items[index].identity.value.clone(),
+                                        prev.name.as_ref().unwrap().span,
+                                        name.span,
+                                    ));
+                                }
+                                None => {
+                                    let normalized = normalize(&key);
+                                    match ret.normalized_names.get(&normalized) {
+                                        Some(&prev) => {
+                                            let prev = ret.items[prev].name.as_ref().unwrap();
+                                            diagnostics.warning(CompileWarning::SimilarNames(
+                                                prev.to_string(),
+                                                key.clone(),
+                                                prev.span,
+                                                name.span,
+                                            ));
+                                        }
+                                        None => {
+                                            ret.normalized_names.insert(normalized, index);
+                                        }
+                                    }
+                                    ret.names.insert(key, index);
+                                }
+                            }
                             ret.items[index].name = Some(name);
-                            ret.names.insert(name, index);
                         }
                     },
                     None => {
-                        return Err(Box::new(CompileError::NoIdentityBeforeName));
+                        diagnostics.error(CompileError::NoIdentityBeforeName(name.span));
                     }
                 },
+                Expr::Head(tag, text) if FieldKind::from_tag(&tag.value).is_some() => {
+                    let kind = FieldKind::from_tag(&tag.value).unwrap();
+                    let index = match index {
+                        Some(index) => index,
+                        None => {
+                            diagnostics.error(CompileError::NoIdentityBeforeField(
+                                kind.tag(),
+                                tag.span.to(text.span),
+                            ));
+                            continue;
+                        }
+                    };
+                    let field = match Field::parse(kind, &tag, text, diagnostics) {
+                        Some(field) => field,
+                        None => continue,
+                    };
+                    let item = &mut ret.items[index];
+                    // 別名以外は 1 つだけ．同じ値なら何度書いてもよい
+                    match item
+                        .fields
+                        .iter()
+                        .find(|prev| prev.kind == kind && !kind.repeatable())
+                    {
+                        Some(prev) if prev.same_value(&field) => {}
+                        Some(prev) => diagnostics.error(CompileError::DuplicateField(
+                            kind.tag(),
+                            item.identity.value.clone(),
+                            prev.span,
+                            field.span,
+                        )),
+                        None => {
+                            if let (FieldKind::Formula, Value::Text(text)) = (kind, &field.value) {
+                                item.formula = Formula::parse(text, diagnostics);
+                            }
+                            item.fields.push(field);
+                        }
+                    }
+                }
+                Expr::Head(tag, text) if tag.value == reaction::TAG => {
+                    if let Some(reaction) = Reaction::parse(&tag, text, diagnostics) {
+                        ret.reactions.push(reaction);
+                    }
+                }
                 Expr::Head(tag, text) => {
-                    ret.headers.push((tag, text));
+                    // タグ名はエスケープせずに出力するので，英数字のみ許す
+                    if tag.value.is_empty() || !tag.value.chars().all(|c| c.is_ascii_alphanumeric())
+                    {
+                        diagnostics.error(CompileError::InvalidTagName(tag.value, tag.span));
+                    } else {
+                        ret.headers.push((tag, text));
+                    }
                 }
                 Expr::Desc(group, text) => match index {
                     Some(index) => {
                         ret.items[index].descs.push(text);
-                        if !group.is_empty() {
-                            match groups.get(group) {
+                        if !group.value.is_empty() {
+                            match groups.get(&group.value) {
                                 Some(value) => {
                                     ret.items[index].groups.insert(*value);
                                 }
                                 None => {
                                     let len = groups.len();
-                                    groups.insert(group, len);
+                                    groups.insert(group.value.clone(), len);
                                     ret.groups.push(group);
                                     ret.items[index].groups.insert(len);
                                 }
@@ -106,17 +358,74 @@ impl<'a, 'b> Document<'a, 'b> {
                         }
                     }
                     None => {
-                        return Err(Box::new(CompileError::NoIdentityBeforeDesc));
+                        diagnostics.error(CompileError::NoIdentityBeforeDesc(text.span));
                     }
                 },
+                Expr::BeginChapter(title) => chapters.push(Chapter {
+                    title,
+                    contents: Vec::new(),
+                }),
+                Expr::EndChapter => close_chapter(&mut chapters),
+            }
+        }
+        while chapters.len() > 1 {
+            close_chapter(&mut chapters);
+        }
+        ret.root = chapters.pop().unwrap();
+        for item in &ret.items {
+            item.check_charge(diagnostics);
+        }
+        // 名前は反応より後に書かれていてもよいので，全て集めてから探す
+        let mut reactions = std::mem::take(&mut ret.reactions);
+        for (index, reaction) in reactions.iter_mut().enumerate() {
+            for participant in reaction.participants_mut() {
+                participant.item = ret.find(&participant.name).map(|(item, _)| item);
+                if let Some(item) = participant.item {
+                    let item = &mut ret.items[item];
+                    // 両辺に現れる化合物もあるので，重ねて加えない
+                    if !item.reactions.contains(&index) {
+                        item.reactions.push(index);
+                    }
+                }
             }
+            reaction.check_balance(&ret.items, diagnostics);
+        }
+        ret.reactions = reactions;
+        ret
+    }
+
+    /// リンクの [ ] の中身から，化合物の番号を探す．
+    /// 完全に一致するものが無ければ，正規化した名前で探す．
+    /// 2 つ目の値は，完全に一致したかどうか．
+    pub fn find(&self, name: &Text) -> Option<(usize, bool)> {
+        match self.names.get(&name.to_string()) {
+            Some(&index) => Some((index, true)),
+            None => self
+                .normalized_names
+                .get(&normalize(&name.to_string()))
+                .map(|&index| (index, false)),
         }
-        Ok(ret)
     }
 
+    /// リンク先が見つからなかったときに，名前や ID がよく似ている化合物の名前を挙げる
+    pub fn suggest(&self, name: &str) -> Vec<String> {
+        let candidates = self.items.iter().filter_map(|item| {
+            let name = item.name.as_ref()?.to_string();
+            Some(vec![
+                (name.clone(), name.clone()),
+                (item.identity.value.clone(), name),
+            ])
+        });
+        suggest(name, candidates.flatten())
+    }
+
+    /// 返すエラーは書き込みの失敗のみで，
+    /// 記述の誤りは diagnostics に記録して出力を続ける．
     pub fn print<Writer: std::io::Write>(
         &self,
         mut writer: &mut Writer,
+        page: &Page,
+        diagnostics: &mut Diagnostics,
     ) -> Result<(), Box<dyn Error>> {
         // <body> 冒頭の <header> タグ内に最終更新日を書くために，
         // ここだけ source に記述せずプログラム内に直接書いている．
@@ -129,57 +438,250 @@ impl<'a, 'b> Document<'a, 'b> {
             <html>\
                 <head>\
                     <meta charset=\"utf-8\">\
-                    <title>化合物から見る代謝経路</title>\
-                    <link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\">\
+                    <title>{}</title>\
+                    <link rel=\"stylesheet\" type=\"text/css\" href=\"{}\">\
                 </head>\
                 <body>\
                     <header>\
-                        <h1>化合物から見る代謝経路</h1>\
+                        <h1>{}</h1>\
                         <p>最終更新日：{}</p>\
                     </header>",
+            Html(page.title),
+            Attr(page.stylesheet),
+            Html(page.title),
             chrono::Utc::now()
                 .with_timezone(&chrono::offset::FixedOffset::east(9 * 3600))
                 .format("%Y/%m/%d"),
         )?;
         for (tag, text) in &self.headers {
-            write!(writer, "<{}>", Display::from(tag))?;
-            text.print(&mut writer, &self)?;
-            write!(writer, "</{}>", Display::from(tag))?;
-        }
-        for item in &self.items {
-            write!(
-                writer,
-                "<div class=\"item\"><div class=\"head\"><p class=\"name\" id=\"{}\">",
-                Display::from(item.identity)
-            )?;
-            match item.name {
-                Some(name) => {
-                    name.print(&mut writer, &self)?;
+            write!(writer, "<{}>", tag.value)?;
+            text.print(&mut writer, self, diagnostics)?;
+            write!(writer, "</{}>", tag.value)?;
+        }
+        // 反応は関わる化合物の数だけ出力するので，先に 1 度だけ書いておく．
+        // リンク切れなどの警告も 1 度だけになる
+        let mut reactions = Vec::new();
+        for reaction in &self.reactions {
+            let mut html = Vec::new();
+            self.print_reaction(&mut html, reaction, diagnostics)?;
+            reactions.push(html);
+        }
+        self.print_chapter(&mut writer, &self.root, 1, &reactions, diagnostics)?;
+        write!(writer, "</body>")?;
+        Ok(())
+    }
+
+    // 章の中身を出力する．
+    // level は見出しのタグ（ h2 など）の数字で，入れ子になるごとに増やす．
+    // reactions は，反応ごとに書いておいた HTML
+    fn print_chapter<Writer: std::io::Write>(
+        &self,
+        writer: &mut Writer,
+        chapter: &Chapter,
+        level: usize,
+        reactions: &[Vec<u8>],
+        diagnostics: &mut Diagnostics,
+    ) -> Result<(), Box<dyn Error>> {
+        for content in &chapter.contents {
+            match content {
+                Content::Item(index) => {
+                    self.print_item(writer, &self.items[*index], reactions, diagnostics)?
                 }
-                None => {
-                    eprintln!(
-                        "error: name of `{}` not provided",
-                        Display::from(item.identity)
-                    );
-                    return Err(Box::new(DocumentPrintError::NoName));
+                Content::Chapter(chapter) => {
+                    // h6 より深くはしない
+                    let level = (level + 1).min(6);
+                    write!(
+                        writer,
+                        "<section class=\"chapter\"><h{level}>{}</h{level}>",
+                        Html(&chapter.title),
+                        level = level
+                    )?;
+                    self.print_chapter(writer, chapter, level, reactions, diagnostics)?;
+                    write!(writer, "</section>")?;
                 }
             }
-            write!(writer, "</p><p class=\"group\">")?;
-            for (i, &group) in item.groups.iter().enumerate() {
+        }
+        Ok(())
+    }
+
+    fn print_item<Writer: std::io::Write>(
+        &self,
+        mut writer: &mut Writer,
+        item: &Item,
+        reactions: &[Vec<u8>],
+        diagnostics: &mut Diagnostics,
+    ) -> Result<(), Box<dyn Error>> {
+        write!(
+            writer,
+            "<div class=\"item\"><div class=\"head\"><p class=\"name\" id=\"{}\">",
+            Attr(&item.identity.value)
+        )?;
+        match &item.name {
+            Some(name) => {
+                name.print(&mut writer, self, diagnostics)?;
+            }
+            None => {
+                // 代わりに ID を見出しにしておく
+                diagnostics.error(DocumentPrintError::NoName(
+                    item.identity.value.clone(),
+                    item.identity.span,
+                ));
+                write!(writer, "{}", Html(&item.identity.value))?;
+            }
+        }
+        write!(writer, "</p><p class=\"group\">")?;
+        for (i, &group) in item.groups.iter().enumerate() {
+            if i != 0 {
+                write!(writer, "・")?;
+            }
+            write!(writer, "{}", Html(&self.groups[group].value))?;
+        }
+        write!(writer, "</p></div>")?;
+        self.print_fields(writer, item, diagnostics)?;
+        write!(writer, "<div class=\"descs\">")?;
+        for desc in &item.descs {
+            write!(writer, "<p class=\"desc\">")?;
+            desc.print(&mut writer, self, diagnostics)?;
+            write!(writer, "</p>")?;
+        }
+        write!(writer, "</div>")?;
+        if !item.reactions.is_empty() {
+            write!(writer, "<ul class=\"reactions\">")?;
+            for &reaction in &item.reactions {
+                write!(writer, "<li class=\"reaction\">")?;
+                writer.write_all(&reactions[reaction])?;
+                write!(writer, "</li>")?;
+            }
+            write!(writer, "</ul>")?;
+        }
+        write!(writer, "</div>")?;
+        Ok(())
+    }
+
+    fn print_reaction<Writer: std::io::Write>(
+        &self,
+        writer: &mut Writer,
+        reaction: &Reaction,
+        diagnostics: &mut Diagnostics,
+    ) -> Result<(), Box<dyn Error>> {
+        write!(writer, "<p class=\"equation\">")?;
+        let arrow = if reaction.reversible { "⇄" } else { "→" };
+        for (i, participant) in reaction
+            .substrates
+            .iter()
+            .chain(&reaction.products)
+            .enumerate()
+        {
+            if i == reaction.substrates.len() {
+                write!(writer, " {} ", arrow)?;
+            } else if i != 0 {
+                write!(writer, " + ")?;
+            }
+            if participant.coefficient != 1 {
+                write!(writer, "{} ", participant.coefficient)?;
+            }
+            // [ ] と書いたのと同じようにリンクにする
+            let link = Text {
+                text: vec![Token::Link(participant.name.clone())],
+                span: participant.name.span,
+            };
+            link.print(writer, self, diagnostics)?;
+        }
+        write!(writer, "</p>")?;
+        let mut infos = Vec::new();
+        if let Some(enzyme) = &reaction.enzyme {
+            infos.push(("酵素：", enzyme));
+        }
+        for cofactor in &reaction.cofactors {
+            infos.push(("補因子：", cofactor));
+        }
+        if let Some(note) = &reaction.note {
+            infos.push(("", note));
+        }
+        if !infos.is_empty() {
+            write!(writer, "<p class=\"reaction_info\">")?;
+            for (i, (label, text)) in infos.into_iter().enumerate() {
+                if i != 0 {
+                    write!(writer, "，")?;
+                }
+                write!(writer, "{}", label)?;
+                text.print(writer, self, diagnostics)?;
+            }
+            write!(writer, "</p>")?;
+        }
+        Ok(())
+    }
+
+    // 情報を表にする．何も無ければ何も出力しない
+    fn print_fields<Writer: std::io::Write>(
+        &self,
+        mut writer: &mut Writer,
+        item: &Item,
+        diagnostics: &mut Diagnostics,
+    ) -> Result<(), Box<dyn Error>> {
+        if item.fields.is_empty() {
+            return Ok(());
+        }
+        let mut fields: Vec<_> = item.fields.iter().collect();
+        fields.sort_by_key(|field| field.kind);
+        write!(writer, "<dl class=\"fields\">")?;
+        // 同じ項目（別名）は 1 行にまとめる
+        for fields in fields.chunk_by(|a, b| a.kind == b.kind) {
+            let kind = fields[0].kind;
+            write!(writer, "<dt>{}</dt><dd>", Html(kind.label()))?;
+            for (i, field) in fields.iter().enumerate() {
                 if i != 0 {
-                    write!(writer, "・")?;
+                    write!(writer, "，")?;
+                }
+                match (&field.value, field.url()) {
+                    (Value::Text(text), _) => text.print(&mut writer, self, diagnostics)?,
+                    (_, Some(url)) => {
+                        write!(writer, "<a href=\"{}\">{}</a>", Attr(&url), Html(field))?
+                    }
+                    (_, None) => write!(writer, "{}", Html(field))?,
                 }
-                write!(writer, "{}", Display::from(self.groups[group]))?;
             }
-            write!(writer, "</p></div><div class=\"descs\">")?;
-            for desc in &item.descs {
-                write!(writer, "<p class=\"desc\">")?;
-                desc.print(&mut writer, &self)?;
-                write!(writer, "</p>")?;
+            write!(writer, "</dd>")?;
+            // 分子式から求めた値を，分子式のすぐ後に並べる
+            if let (FieldKind::Formula, Some(formula)) = (kind, &item.formula) {
+                print_formula(writer, formula)?;
             }
-            write!(writer, "</div></div>")?;
         }
-        write!(writer, "</body>")?;
+        write!(writer, "</dl>")?;
         Ok(())
     }
 }
+
+fn print_formula<Writer: std::io::Write>(
+    writer: &mut Writer,
+    formula: &Formula,
+) -> Result<(), Box<dyn Error>> {
+    write!(
+        writer,
+        "<dt>分子量</dt><dd>{:.3} g/mol</dd>\
+        <dt>モノアイソトピック質量</dt><dd>{:.4} Da</dd>\
+        <dt>組成</dt><dd>",
+        formula.average_mass(),
+        formula.monoisotopic_mass(),
+    )?;
+    for (i, (symbol, percent)) in formula.composition().into_iter().enumerate() {
+        if i != 0 {
+            write!(writer, "，")?;
+        }
+        write!(writer, "{} {:.2}%", symbol, percent)?;
+    }
+    write!(writer, "</dd>")?;
+    Ok(())
+}
+
+// 最も内側の章を閉じて，外側の章の中身に加える
+fn close_chapter(chapters: &mut Vec<Chapter>) {
+    if chapters.len() > 1 {
+        let chapter = chapters.pop().unwrap();
+        chapters
+            .last_mut()
+            .unwrap()
+            .contents
+            .push(Content::Chapter(chapter));
+    }
+}
@@ -0,0 +1,76 @@
+// index.html に文字列を埋め込むときのエスケープ．
+// Html(…) ， Attr(…) ， Fragment(…) で包んでから write! に渡すと，
+// 中身を Display で出力した結果がエスケープされる．
+use std::fmt;
+
+/// 要素の中身（テキスト）として埋め込むとき
+pub struct Html<T>(pub T);
+
+/// 属性値（ id="…" など）として埋め込むとき
+pub struct Attr<T>(pub T);
+
+/// href="#…" の # 以降として埋め込むとき．
+/// 英数字と一部の記号以外はパーセントエンコードする．
+/// 結果には " や & が含まれないので，属性値の中にそのまま書ける．
+pub struct Fragment<T>(pub T);
+
+// 1 文字ずつエスケープしながら Formatter に書き込む
+struct Escaper<'a, 'b> {
+    f: &'a mut fmt::Formatter<'b>,
+    escape: fn(char, &mut fmt::Formatter) -> fmt::Result,
+}
+
+impl<'a, 'b> fmt::Write for Escaper<'a, 'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            (self.escape)(c, self.f)?;
+        }
+        Ok(())
+    }
+}
+
+fn escape_html(c: char, f: &mut fmt::Formatter) -> fmt::Result {
+    match c {
+        '&' => f.write_str("&amp;"),
+        '<' => f.write_str("&lt;"),
+        '>' => f.write_str("&gt;"),
+        _ => fmt::Write::write_char(f, c),
+    }
+}
+
+fn escape_attr(c: char, f: &mut fmt::Formatter) -> fmt::Result {
+    match c {
+        '"' => f.write_str("&quot;"),
+        '\'' => f.write_str("&#39;"),
+        _ => escape_html(c, f),
+    }
+}
+
+fn escape_fragment(c: char, f: &mut fmt::Formatter) -> fmt::Result {
+    match c {
+        c if c.is_ascii_alphanumeric() => fmt::Write::write_char(f, c),
+        '-' | '.' | '_' | '~' | ',' => fmt::Write::write_char(f, c),
+        _ => {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                write!(f, "%{:02X}", byte)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+macro_rules! impl_escape {
+    ($wrapper:ident, $escape:ident) => {
+        impl<T: fmt::Display> fmt::Display for $wrapper<T> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                let mut escaper = Escaper { f, escape: $escape };
+                fmt::Write::write_fmt(&mut escaper, format_args!("{}", self.0))
+            }
+        }
+    };
+}
+
+impl_escape!(Html, escape_html);
+impl_escape!(Attr, escape_attr);
+impl_escape!(Fragment, escape_fragment);
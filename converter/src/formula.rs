@@ -0,0 +1,616 @@
+// \formula{ } に書かれた分子式を読み，元素ごとの原子の数と電荷を求める．
+//
+//     C_6H_{12}O_6        個数は下付きで書く．2 桁以上は { } でくくる
+//     Ca(OH)_2            丸括弧でくくった部分にも個数を付けられる
+//     CuSO_4・5H_2O       水和物は ・（ · や . でもよい）でつなぎ，先頭に係数を書く
+//     PO_4^{3-}           電荷は最後に上付きで書く
+
+use super::diagnostic::{Diagnostic, Diagnostics};
+use super::metadata::parse_charge;
+use super::source_map::Span;
+use super::text::{Text, Token};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+// 電子の質量．イオンのモノアイソトピック質量から差し引く
+const ELECTRON_MASS: f64 = 0.000_548_579_909;
+
+// 元素記号，標準原子量，最も存在比の大きい同位体の質量．
+// 安定同位体が無く標準原子量の定まらない元素は含めない
+const ELEMENTS: &[(&str, f64, f64)] = &[
+    ("H", 1.008, 1.007_825_032),
+    ("He", 4.002_602, 4.002_603_254),
+    ("Li", 6.94, 7.016_003_437),
+    ("Be", 9.012_183, 9.012_183_065),
+    ("B", 10.81, 11.009_305_36),
+    ("C", 12.011, 12.0),
+    ("N", 14.007, 14.003_074_004),
+    ("O", 15.999, 15.994_914_620),
+    ("F", 18.998_403, 18.998_403_163),
+    ("Ne", 20.1797, 19.992_440_176),
+    ("Na", 22.989_770, 22.989_769_282),
+    ("Mg", 24.305, 23.985_041_697),
+    ("Al", 26.981_538, 26.981_538_53),
+    ("Si", 28.085, 27.976_926_535),
+    ("P", 30.973_762, 30.973_761_998),
+    ("S", 32.06, 31.972_071_174),
+    ("Cl", 35.45, 34.968_852_682),
+    ("Ar", 39.948, 39.962_383_124),
+    ("K", 39.0983, 38.963_706_486),
+    ("Ca", 40.078, 39.962_590_863),
+    ("Sc", 44.955_908, 44.955_908_28),
+    ("Ti", 47.867, 47.947_941_98),
+    ("V", 50.9415, 50.943_957_04),
+    ("Cr", 51.9961, 51.940_506_23),
+    ("Mn", 54.938_044, 54.938_043_91),
+    ("Fe", 55.845, 55.934_936_33),
+    ("Co", 58.933_194, 58.933_194_29),
+    ("Ni", 58.6934, 57.935_342_41),
+    ("Cu", 63.546, 62.929_597_72),
+    ("Zn", 65.38, 63.929_142_01),
+    ("Ga", 69.723, 68.925_573_5),
+    ("Ge", 72.630, 73.921_177_761),
+    ("As", 74.921_595, 74.921_594_57),
+    ("Se", 78.971, 79.916_521_8),
+    ("Br", 79.904, 78.918_337_6),
+    ("Kr", 83.798, 83.911_497_728),
+    ("Rb", 85.4678, 84.911_789_738),
+    ("Sr", 87.62, 87.905_612_5),
+    ("Y", 88.905_84, 88.905_840_3),
+    ("Zr", 91.224, 89.904_697_7),
+    ("Nb", 92.906_37, 92.906_373),
+    ("Mo", 95.95, 97.905_404_82),
+    ("Ru", 101.07, 101.904_344_1),
+    ("Rh", 102.905_49, 102.905_498),
+    ("Pd", 106.42, 105.903_480_4),
+    ("Ag", 107.8682, 106.905_091_6),
+    ("Cd", 112.414, 113.903_365_09),
+    ("In", 114.818, 114.903_878_776),
+    ("Sn", 118.710, 119.902_201_63),
+    ("Sb", 121.760, 120.903_812),
+    ("Te", 127.60, 129.906_222_748),
+    ("I", 126.904_47, 126.904_471_9),
+    ("Xe", 131.293, 131.904_155_086),
+    ("Cs", 132.905_452, 132.905_451_961),
+    ("Ba", 137.327, 137.905_247),
+    ("La", 138.905_47, 138.906_356_3),
+    ("Ce", 140.116, 139.905_443_1),
+    ("Pr", 140.907_66, 140.907_657_6),
+    ("Nd", 144.242, 141.907_729),
+    ("Sm", 150.36, 151.919_739_7),
+    ("Eu", 151.964, 152.921_238),
+    ("Gd", 157.25, 157.924_112_3),
+    ("Tb", 158.925_35, 158.925_354_7),
+    ("Dy", 162.500, 163.929_181_9),
+    ("Ho", 164.930_33, 164.930_328_8),
+    ("Er", 167.259, 165.930_299_5),
+    ("Tm", 168.934_22, 168.934_217_9),
+    ("Yb", 173.045, 173.938_866_4),
+    ("Lu", 174.9668, 174.940_775_2),
+    ("Hf", 178.49, 179.946_557),
+    ("Ta", 180.947_88, 180.947_995_8),
+    ("W", 183.84, 183.950_930_92),
+    ("Re", 186.207, 186.955_750_1),
+    ("Os", 190.23, 191.961_477),
+    ("Ir", 192.217, 192.962_921_6),
+    ("Pt", 195.084, 194.964_791_7),
+    ("Au", 196.966_569, 196.966_568_79),
+    ("Hg", 200.592, 201.970_643_4),
+    ("Tl", 204.38, 204.974_427_8),
+    ("Pb", 207.2, 207.976_652_5),
+    ("Bi", 208.980_40, 208.980_399_1),
+    ("Th", 232.0377, 232.038_055_8),
+    ("Pa", 231.035_88, 231.035_884_2),
+    ("U", 238.028_91, 238.050_788_4),
+];
+
+// 水和物などをつなぐ記号
+const SEPARATORS: &[char] = &['・', '·', '•', '.', '*'];
+
+/// 分子式から求めた，元素ごとの原子の数と電荷
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Formula {
+    pub atoms: BTreeMap<String, u64>,
+    pub charge: i64,
+}
+
+#[derive(thiserror::Error, Debug)]
+enum FormulaError {
+    #[error("unknown element `{0}`")]
+    UnknownElement(String, Span),
+    #[error("multi-digit subscript `{0}` must be enclosed in braces")]
+    UnbracedSubscript(String, Span),
+    #[error("count `{0}` is not written as a subscript")]
+    PlainCount(String, Span),
+    #[error("`{0}` is not a valid count")]
+    InvalidCount(String, Span),
+    #[error("`{0}` is not a valid charge")]
+    InvalidCharge(String, Span),
+    #[error("charge must come at the end of the formula")]
+    MisplacedCharge(Span),
+    #[error("unexpected `{0}` in formula")]
+    Unexpected(String, Span),
+    #[error("empty formula")]
+    Empty(Span),
+}
+
+impl From<FormulaError> for Diagnostic {
+    fn from(err: FormulaError) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&err);
+        match err {
+            FormulaError::UnknownElement(_, span) => diagnostic
+                .label(span, "no such element")
+                .help("element symbols start with an uppercase letter, like `Ca` or `Cl`"),
+            FormulaError::UnbracedSubscript(digits, span) => diagnostic
+                .label(span, "only the first digit is a subscript")
+                .help(format!("write `_{{{}}}`", digits)),
+            FormulaError::PlainCount(digits, span) => {
+                let diagnostic = diagnostic.label(span, "not a subscript");
+                if digits.len() == 1 {
+                    diagnostic.help(format!("write `_{}`", digits))
+                } else {
+                    diagnostic.help(format!("write `_{{{}}}`", digits))
+                }
+            }
+            FormulaError::InvalidCount(_, span) => {
+                diagnostic.label(span, "expected a positive integer")
+            }
+            FormulaError::InvalidCharge(_, span) => diagnostic
+                .label(span, "invalid charge")
+                .help("write it like `^+`, `^-` or `^{2+}`"),
+            FormulaError::MisplacedCharge(span) => diagnostic.label(span, "charge written here"),
+            FormulaError::Unexpected(s, span) => {
+                let diagnostic = diagnostic.label(span, "unexpected");
+                if s.starts_with(|c: char| c.is_ascii_lowercase()) {
+                    diagnostic.help("element symbols start with an uppercase letter")
+                } else {
+                    diagnostic
+                }
+            }
+            FormulaError::Empty(span) => diagnostic.label(span, "expected an element here"),
+        }
+    }
+}
+
+// Text を 1 文字ずつの単位に分けたもの
+enum Piece {
+    Char(char, Span),
+    Sub(String, Span, bool), // 下付き．最後の値は { } でくくられていたかどうか
+    Sup(String, Span),
+    Group(Vec<Piece>, Span), // 丸括弧
+}
+
+impl Formula {
+    /// text を分子式として読む．
+    /// 誤りがあれば，最初のものを diagnostics に記録して None を返す
+    pub fn parse(text: &Text, diagnostics: &mut Diagnostics) -> Option<Formula> {
+        match pieces(text).and_then(|pieces| {
+            let mut parser = Parser {
+                pieces: &pieces,
+                pos: 0,
+                end: text.span,
+            };
+            parser.formula()
+        }) {
+            Ok(formula) => Some(formula),
+            Err(err) => {
+                diagnostics.error(err);
+                None
+            }
+        }
+    }
+
+    /// 平均分子量（ g/mol ）
+    pub fn average_mass(&self) -> f64 {
+        self.atoms
+            .iter()
+            .map(|(symbol, &count)| element(symbol).map_or(0., |e| e.1) * count as f64)
+            .sum()
+    }
+
+    /// モノアイソトピック質量（ Da ）．イオンでは電子の分を増減する
+    pub fn monoisotopic_mass(&self) -> f64 {
+        let mass: f64 = self
+            .atoms
+            .iter()
+            .map(|(symbol, &count)| element(symbol).map_or(0., |e| e.2) * count as f64)
+            .sum();
+        mass - ELECTRON_MASS * self.charge as f64
+    }
+
+    /// 元素を Hill 方式の順（炭素，水素，残りはアルファベット順）に並べる．
+    /// 炭素を含まないときは全てアルファベット順
+    pub fn hill_order(&self) -> Vec<(&str, u64)> {
+        let mut ret: Vec<_> = self
+            .atoms
+            .iter()
+            .map(|(symbol, &count)| (symbol.as_str(), count))
+            .collect();
+        if self.atoms.contains_key("C") {
+            ret.sort_by_key(|&(symbol, _)| match symbol {
+                "C" => 0,
+                "H" => 1,
+                _ => 2,
+            });
+        }
+        ret
+    }
+
+    /// 元素ごとの質量百分率．並びは hill_order と同じ
+    pub fn composition(&self) -> Vec<(&str, f64)> {
+        let total = self.average_mass();
+        self.hill_order()
+            .into_iter()
+            .map(|(symbol, count)| {
+                (
+                    symbol,
+                    element(symbol).map_or(0., |e| e.1) * count as f64 / total * 100.,
+                )
+            })
+            .collect()
+    }
+
+    // other を times 倍して加える．桁あふれしたら None
+    pub(crate) fn add(&mut self, other: Formula, times: u64) -> Option<()> {
+        for (symbol, count) in other.atoms {
+            let sum = self.atoms.entry(symbol).or_default();
+            *sum = sum.checked_add(count.checked_mul(times)?)?;
+        }
+        let charge = other.charge.checked_mul(i64::try_from(times).ok()?)?;
+        self.charge = self.charge.checked_add(charge)?;
+        Some(())
+    }
+}
+
+fn element(symbol: &str) -> Option<&'static (&'static str, f64, f64)> {
+    ELEMENTS.iter().find(|(s, _, _)| *s == symbol)
+}
+
+fn pieces(text: &Text) -> Result<Vec<Piece>, FormulaError> {
+    let mut ret = Vec::new();
+    let mut iter = text.text.iter();
+    while let Some(token) = iter.next() {
+        match token {
+            Token::Str(s, span) => {
+                for (i, c) in s.char_indices() {
+                    if !c.is_whitespace() {
+                        ret.push(Piece::Char(c, span.sub(s, i, c.len_utf8())));
+                    }
+                }
+            }
+            Token::Escaped(c, span) => ret.push(Piece::Char(*c, *span)),
+            Token::Sub(span) | Token::Sup(span) => {
+                let (s, target, braced) = match iter.next() {
+                    Some(Token::Str(s, target)) => (s.clone(), *target, false),
+                    Some(Token::Block(text)) => {
+                        (text.to_string().trim().to_string(), text.span, true)
+                    }
+                    Some(token) => {
+                        return Err(FormulaError::Unexpected(token.to_string(), token.span()))
+                    }
+                    // 何も続かないことは，出力するときに報告される
+                    None => break,
+                };
+                let span = span.to(target);
+                ret.push(match token {
+                    Token::Sub(_) => Piece::Sub(s, span, braced),
+                    _ => Piece::Sup(s, span),
+                });
+            }
+            Token::Paren(text) => ret.push(Piece::Group(pieces(text)?, text.span)),
+            Token::Block(text) => ret.extend(pieces(text)?),
+            token => return Err(FormulaError::Unexpected(token.to_string(), token.span())),
+        }
+    }
+    Ok(ret)
+}
+
+struct Parser<'a> {
+    pieces: &'a [Piece],
+    pos: usize,
+    end: Span, // 何も無いところで誤りを見つけたときに指す位置
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Piece> {
+        self.pieces.get(self.pos)
+    }
+
+    // 全体．水和物などを・でつないだものと，最後の電荷
+    fn formula(&mut self) -> Result<Formula, FormulaError> {
+        let mut ret = self.part()?;
+        while let Some(Piece::Char(c, _)) = self.peek() {
+            if !SEPARATORS.contains(c) {
+                break;
+            }
+            self.pos += 1;
+            let start = self.pos;
+            let part = self.part()?;
+            if ret.add(part, 1).is_none() {
+                return Err(self.overflow(start));
+            }
+        }
+        if let Some(Piece::Sup(s, span)) = self.peek() {
+            ret.charge =
+                parse_charge(s).ok_or_else(|| FormulaError::InvalidCharge(s.clone(), *span))?;
+            self.pos += 1;
+        }
+        match self.peek() {
+            None => Ok(ret),
+            Some(piece) => Err(self.unexpected(piece)),
+        }
+    }
+
+    // ・で区切られた 1 つ分．先頭に係数を付けられる
+    fn part(&mut self) -> Result<Formula, FormulaError> {
+        let start = self.pos;
+        let mut coefficient = String::new();
+        while let Some(Piece::Char(c, _)) = self.peek() {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            coefficient.push(*c);
+            self.pos += 1;
+        }
+        let times = match coefficient.as_str() {
+            "" => 1,
+            s => match s.parse::<u64>() {
+                Ok(times) if times > 0 => times,
+                _ => {
+                    let span =
+                        piece_span(&self.pieces[start]).to(piece_span(&self.pieces[self.pos - 1]));
+                    return Err(FormulaError::InvalidCount(coefficient, span));
+                }
+            },
+        };
+        let formula = self.sequence()?;
+        let mut ret = Formula::default();
+        if ret.add(formula, times).is_none() {
+            return Err(self.overflow(start));
+        }
+        Ok(ret)
+    }
+
+    // 元素や丸括弧の並び
+    fn sequence(&mut self) -> Result<Formula, FormulaError> {
+        let mut ret = Formula::default();
+        loop {
+            let start = self.pos;
+            let formula = match self.peek() {
+                Some(Piece::Char(c, span)) if c.is_ascii_uppercase() => {
+                    self.pos += 1;
+                    let mut symbol = c.to_string();
+                    let mut span = *span;
+                    while let Some(Piece::Char(c, next)) = self.peek() {
+                        if !c.is_ascii_lowercase() {
+                            break;
+                        }
+                        symbol.push(*c);
+                        span = span.to(*next);
+                        self.pos += 1;
+                    }
+                    if element(&symbol).is_none() {
+                        return Err(FormulaError::UnknownElement(symbol, span));
+                    }
+                    let mut formula = Formula::default();
+                    formula.atoms.insert(symbol, 1);
+                    formula
+                }
+                Some(Piece::Group(pieces, span)) => {
+                    self.pos += 1;
+                    let mut parser = Parser {
+                        pieces,
+                        pos: 0,
+                        end: *span,
+                    };
+                    let formula = parser.sequence()?;
+                    if let Some(piece) = parser.peek() {
+                        return Err(parser.unexpected(piece));
+                    }
+                    formula
+                }
+                Some(Piece::Sup(_, span)) if self.pos + 1 < self.pieces.len() => {
+                    return Err(FormulaError::MisplacedCharge(*span))
+                }
+                _ => break,
+            };
+            let count = self.count()?;
+            if ret.add(formula, count).is_none() {
+                return Err(self.overflow(start));
+            }
+        }
+        if ret.atoms.is_empty() {
+            return Err(match self.peek() {
+                Some(piece) => self.unexpected(piece),
+                None => FormulaError::Empty(self.end),
+            });
+        }
+        Ok(ret)
+    }
+
+    // 元素や丸括弧の直後の個数．書かれていなければ 1
+    fn count(&mut self) -> Result<u64, FormulaError> {
+        match self.peek() {
+            Some(Piece::Sub(s, span, braced)) => {
+                self.pos += 1;
+                let count = match s.parse::<u64>() {
+                    Ok(count) if count > 0 && s.chars().all(|c| c.is_ascii_digit()) => count,
+                    _ => return Err(FormulaError::InvalidCount(s.clone(), *span)),
+                };
+                // C_12 と書くと， 1 だけが下付きになる
+                if !braced {
+                    let mut digits = s.clone();
+                    let mut end = *span;
+                    while let Some(Piece::Char(c, next)) = self.peek() {
+                        if !c.is_ascii_digit() {
+                            break;
+                        }
+                        digits.push(*c);
+                        end = *next;
+                        self.pos += 1;
+                    }
+                    if digits.len() > s.len() {
+                        return Err(FormulaError::UnbracedSubscript(digits, span.to(end)));
+                    }
+                }
+                Ok(count)
+            }
+            Some(Piece::Char(c, span)) if c.is_ascii_digit() => {
+                let mut digits = String::new();
+                let mut end = *span;
+                while let Some(Piece::Char(c, next)) = self.peek() {
+                    if !c.is_ascii_digit() {
+                        break;
+                    }
+                    digits.push(*c);
+                    end = *next;
+                    self.pos += 1;
+                }
+                Err(FormulaError::PlainCount(digits, span.to(end)))
+            }
+            _ => Ok(1),
+        }
+    }
+
+    // start 番目から直前までを掛け合わせたら，原子の数が u64 に収まらなかった
+    fn overflow(&self, start: usize) -> FormulaError {
+        let pieces = &self.pieces[start..self.pos];
+        let span = piece_span(&pieces[0]).to(piece_span(&pieces[pieces.len() - 1]));
+        let s = pieces.iter().map(piece_string).collect();
+        FormulaError::InvalidCount(s, span)
+    }
+
+    fn unexpected(&self, piece: &Piece) -> FormulaError {
+        let s = match piece {
+            Piece::Group(..) => "(".to_string(),
+            piece => piece_string(piece),
+        };
+        FormulaError::Unexpected(s, piece_span(piece))
+    }
+}
+
+// エラーメッセージに出すための，書かれたのに近い形
+fn piece_string(piece: &Piece) -> String {
+    match piece {
+        Piece::Char(c, _) => c.to_string(),
+        Piece::Sub(s, _, true) => format!("_{{{}}}", s),
+        Piece::Sub(s, _, false) => format!("_{}", s),
+        Piece::Sup(s, _) => format!("^{{{}}}", s),
+        Piece::Group(pieces, _) => {
+            let inner: String = pieces.iter().map(piece_string).collect();
+            format!("({})", inner)
+        }
+    }
+}
+
+fn piece_span(piece: &Piece) -> Span {
+    match piece {
+        Piece::Char(_, span)
+        | Piece::Sub(_, span, _)
+        | Piece::Sup(_, span)
+        | Piece::Group(_, span) => *span,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Formula;
+    use crate::test_util::document;
+    use crate::Diagnostics;
+
+    // \formula{ } に s を書いた化合物を読む
+    fn formula(s: &str) -> (Option<Formula>, Diagnostics) {
+        let (mut document, diagnostics) = document(&format!("x [x] \\formula{{{}}}", s));
+        (document.items.remove(0).formula, diagnostics)
+    }
+
+    #[test]
+    fn parse_formula() {
+        // (分子式, 元素ごとの原子の数, 電荷)
+        type Case = (&'static str, &'static [(&'static str, u64)], i64);
+        let cases: &[Case] = &[
+            ("H_2O", &[("H", 2), ("O", 1)], 0),
+            ("C_6H_{12}O_6", &[("C", 6), ("H", 12), ("O", 6)], 0),
+            ("CH_3COOH", &[("C", 2), ("H", 4), ("O", 2)], 0),
+            ("Ca(OH)_2", &[("Ca", 1), ("H", 2), ("O", 2)], 0),
+            ("(CH_3(CH_2)_2)_2O", &[("C", 6), ("H", 14), ("O", 1)], 0),
+            (
+                "CuSO_4・5H_2O",
+                &[("Cu", 1), ("H", 10), ("O", 9), ("S", 1)],
+                0,
+            ),
+            (
+                "CaSO_4.2H_2O",
+                &[("Ca", 1), ("H", 4), ("O", 6), ("S", 1)],
+                0,
+            ),
+            ("2H_2O", &[("H", 4), ("O", 2)], 0),
+            ("PO_4^{3-}", &[("O", 4), ("P", 1)], -3),
+            ("NH_4^+", &[("H", 4), ("N", 1)], 1),
+            ("Fe^{2+}", &[("Fe", 1)], 2),
+            ("Cl^{−}", &[("Cl", 1)], -1),
+        ];
+        for &(s, atoms, charge) in cases {
+            let (formula, diagnostics) = formula(s);
+            assert!(!diagnostics.has_errors(), "{}", s);
+            let formula = formula.unwrap();
+            let expected: Vec<_> = atoms.iter().map(|&(e, n)| (e.to_string(), n)).collect();
+            let actual: Vec<_> = formula.atoms.into_iter().collect();
+            assert_eq!(actual, expected, "{}", s);
+            assert_eq!(formula.charge, charge, "{}", s);
+        }
+    }
+
+    #[test]
+    fn invalid_formula() {
+        let cases = [
+            "C_6Xy_2",
+            "h_2o",
+            "C6H12O6",
+            "H_12",
+            "H_{0}",
+            "SO_4^{x}",
+            "Fe^{2+}Cl",
+            "0H_2O",
+            // 桁あふれ
+            "999999999999999999999H_2O",
+            "C_{99999999999999999999}",
+            "C_{99999999999}(C_{99999999999})_{99999999999}",
+            "C_{18446744073709551615}C",
+            "1000000000000C_{100000000000}",
+            "C_{10000000000}・C_{10000000000}・C_{18446744063709551615}",
+        ];
+        for s in cases {
+            let (formula, diagnostics) = formula(s);
+            assert!(formula.is_none(), "{}", s);
+            assert_eq!(diagnostics.error_count(), 1, "{}", s);
+        }
+    }
+
+    #[test]
+    fn mass() {
+        // (分子式, 平均分子量, モノアイソトピック質量)
+        let cases = [
+            ("H_2O", 18.015, 18.0106),
+            ("C_6H_{12}O_6", 180.156, 180.0634),
+            ("CuSO_4・5H_2O", 249.677, 248.9342),
+            ("NH_4^+", 18.039, 18.0338),
+        ];
+        for (s, average, monoisotopic) in cases {
+            let formula = formula(s).0.unwrap();
+            assert_eq!(
+                format!("{:.3}", formula.average_mass()),
+                format!("{:.3}", average),
+                "{}",
+                s
+            );
+            assert_eq!(
+                format!("{:.4}", formula.monoisotopic_mass()),
+                format!("{:.4}", monoisotopic),
+                "{}",
+                s
+            );
+        }
+    }
+}
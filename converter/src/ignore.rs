@@ -0,0 +1,136 @@
+// source ディレクトリ直下の .biochemignore に書かれたパターン．
+// 1 行に 1 つずつ書き，空行と # で始まる行は読み飛ばす．
+//
+// * は / 以外の任意の文字列， ** は / も含めた任意の文字列， ? は / 以外の任意の 1 文字にマッチする．
+// / を含むパターンは source ディレクトリからの相対パスと比べ，
+// 含まないパターンはどの階層でもファイル名やディレクトリ名と比べる．
+// 末尾に / を付けたパターンは，ディレクトリにだけマッチする．
+
+use std::io;
+use std::path::Path;
+
+pub const FILE_NAME: &str = ".biochemignore";
+
+#[derive(Default)]
+pub struct Patterns {
+    patterns: Vec<Pattern>,
+}
+
+struct Pattern {
+    line: String, // 書かれていたままの行（理由の表示に使う）
+    glob: Vec<char>,
+    anchored: bool, // source ディレクトリからの相対パスと比べるか
+    dir_only: bool,
+}
+
+impl Patterns {
+    /// root 直下の .biochemignore を読む．無ければパターンは空
+    pub fn load(root: &Path) -> io::Result<Patterns> {
+        let text = match std::fs::read_to_string(root.join(FILE_NAME)) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Patterns::default()),
+            Err(err) => return Err(err),
+        };
+        Ok(Patterns::parse(&text))
+    }
+
+    fn parse(text: &str) -> Patterns {
+        let patterns = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let dir_only = line.ends_with('/');
+                let glob = line.trim_end_matches('/');
+                let anchored = glob.contains('/');
+                Pattern {
+                    line: line.to_string(),
+                    glob: glob.trim_start_matches('/').chars().collect(),
+                    anchored,
+                    dir_only,
+                }
+            })
+            .collect();
+        Patterns { patterns }
+    }
+
+    /// relative は source ディレクトリからの相対パス（区切りは / ）．
+    /// マッチしたパターンがあれば，その行を返す
+    pub fn find(&self, relative: &str, is_dir: bool) -> Option<&str> {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        let relative: Vec<char> = relative.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        self.patterns
+            .iter()
+            .find(|pattern| {
+                (is_dir || !pattern.dir_only)
+                    && glob(
+                        &pattern.glob,
+                        if pattern.anchored { &relative } else { &name },
+                    )
+            })
+            .map(|pattern| pattern.line.as_str())
+    }
+}
+
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            // "**/" は 0 個以上のディレクトリなので，先頭か / の直後からしか続けられない
+            glob(rest, text)
+                || (0..text.len()).any(|i| text[i] == '/' && glob(rest, &text[i + 1..]))
+        }
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        ['*', rest @ ..] => {
+            let len = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+            (0..=len).any(|i| glob(rest, &text[i..]))
+        }
+        ['?', rest @ ..] => matches!(text, [c, ..] if *c != '/') && glob(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Patterns;
+
+    #[test]
+    fn find() {
+        // (パターン, 相対パス, ディレクトリか, マッチするか)
+        let cases = [
+            ("**/foo", "foo", false, true),
+            ("**/foo", "20_x/foo", false, true),
+            ("**/foo", "20_x/1_y/foo", false, true),
+            ("**/foo", "20_x/1_keepfoo", false, false),
+            ("**/foo", "foo/1_x", false, false),
+            ("a/**/b", "a/b", false, true),
+            ("a/**/b", "a/x/b", false, true),
+            ("a/**/b", "a/x/y/b", false, true),
+            ("a/**/b", "a/xb", false, false),
+            ("a/**/b", "ab", false, false),
+            ("a/**", "a/x/y", false, true),
+            ("drafts/", "drafts", true, true),
+            ("drafts/", "drafts", false, false),
+            ("drafts/", "10_x/drafts", true, true),
+            ("/10_x", "10_x", true, true),
+            ("/10_x", "20_y/10_x", true, false),
+            ("20_y/*.txt", "20_y/a.txt", false, true),
+            ("20_y/*.txt", "30_z/20_y/a.txt", false, false),
+            ("20_y/*.txt", "20_y/b/a.txt", false, false),
+            ("*.txt", "20_y/a.txt", false, true),
+            ("?_x", "1_x", false, true),
+            ("?_x", "10_x", false, false),
+        ];
+        for (pattern, relative, is_dir, expected) in cases {
+            let patterns = Patterns::parse(pattern);
+            assert_eq!(
+                patterns.find(relative, is_dir).is_some(),
+                expected,
+                "pattern {:?}, path {:?}",
+                pattern,
+                relative
+            );
+        }
+    }
+}
@@ -0,0 +1,131 @@
+// \include{path} を，path のファイルの中身で置き換える．
+// path は \include を書いたファイルのあるディレクトリからの相対パス．
+// 取り込んだファイルも source_map に追加するので，
+// その中の誤りも取り込んだファイル自身の位置で報告される．
+
+use super::diagnostic::{Diagnostic, Diagnostics};
+use super::read_files::{decode, Encoding};
+use super::source::{Expr, Source, Word};
+use super::source_map::{SourceMap, Span};
+use super::text::Text;
+use std::path::{Path, PathBuf};
+
+pub const TAG: &str = "include";
+
+#[derive(thiserror::Error, Debug)]
+enum IncludeError {
+    #[error("cannot read `{0}`: {1}")]
+    Unreadable(String, String, Span),
+    #[error("cyclic include of `{0}`")]
+    Cycle(String, Span, Vec<String>), // 取り込みの連鎖も付ける
+}
+
+impl From<IncludeError> for Diagnostic {
+    fn from(err: IncludeError) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&err);
+        match err {
+            IncludeError::Unreadable(_, _, span) => diagnostic
+                .label(span, "included here")
+                .help("the path is relative to the file containing `\\include`"),
+            IncludeError::Cycle(_, span, chain) => diagnostic
+                .label(span, "included again here")
+                .help(format!("include chain: {}", chain.join(" -> "))),
+        }
+    }
+}
+
+/// file をパースし，中の \include{path} を再帰的に展開する．
+/// 読めないファイルや循環した取り込みは diagnostics に記録し，その \include は無視する
+pub fn parse_file(
+    file: usize,
+    source_map: &mut SourceMap,
+    encoding: Encoding,
+    diagnostics: &mut Diagnostics,
+) -> Vec<Expr> {
+    let path = source_map.file(file).path.clone();
+    let mut including = vec![(key(&path), path)];
+    expand(file, source_map, encoding, &mut including, diagnostics)
+}
+
+// including は，取り込みの途中にあるファイル（循環の検出に使う）．
+// 比べるための絶対パスと，メッセージに出すためのパスの組
+fn expand(
+    file: usize,
+    source_map: &mut SourceMap,
+    encoding: Encoding,
+    including: &mut Vec<(PathBuf, PathBuf)>,
+    diagnostics: &mut Diagnostics,
+) -> Vec<Expr> {
+    let exprs = Source::from(source_map, file).parse(diagnostics);
+    let mut ret = Vec::new();
+    for expr in exprs {
+        match expr {
+            Expr::Head(tag, text) if tag.value == TAG => {
+                ret.extend(include(
+                    file,
+                    &tag,
+                    &text,
+                    source_map,
+                    encoding,
+                    including,
+                    diagnostics,
+                ));
+            }
+            expr => ret.push(expr),
+        }
+    }
+    ret
+}
+
+fn include(
+    file: usize,
+    tag: &Word,
+    text: &Text,
+    source_map: &mut SourceMap,
+    encoding: Encoding,
+    including: &mut Vec<(PathBuf, PathBuf)>,
+    diagnostics: &mut Diagnostics,
+) -> Vec<Expr> {
+    let span = tag.span.to(text.span);
+    let name = text.to_string();
+    let path = match source_map.file(file).path.parent() {
+        Some(dir) => dir.join(name.trim()),
+        None => PathBuf::from(name.trim()),
+    };
+    let key = key(&path);
+    if including.iter().any(|(prev, _)| *prev == key) {
+        let chain = including
+            .iter()
+            .map(|(_, path)| path)
+            .chain(Some(&path))
+            .map(|path| path.display().to_string())
+            .collect();
+        diagnostics.error(IncludeError::Cycle(path.display().to_string(), span, chain));
+        return Vec::new();
+    }
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            diagnostics.error(IncludeError::Unreadable(
+                path.display().to_string(),
+                err.to_string(),
+                span,
+            ));
+            return Vec::new();
+        }
+    };
+    let included = match decode(&path, &bytes, encoding, source_map, diagnostics) {
+        Some(included) => included,
+        None => return Vec::new(),
+    };
+    including.push((key, path));
+    let ret = expand(included, source_map, encoding, including, diagnostics);
+    including.pop();
+    ret
+}
+
+// 同じファイルを別の書き方で取り込んでも循環を見つけられるよう，絶対パスにする．
+// 実在しないファイル（文字列から読んだものなど）はそのまま
+fn key(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
@@ -0,0 +1,112 @@
+//! source ディレクトリに書かれた記事を HTML に変換する．
+//!
+//! 変換は次の順に行う．
+//!
+//! 1. [`search_dir()`] でディレクトリ内のファイルとサブディレクトリを番号順に並べ，
+//!    [`read_files()`] で [`SourceMap`] に読み込む
+//!    （文字列から読むときは [`read_str`] ）．
+//!    サブディレクトリは章になる．
+//! 2. [`parse`] で [`Expr`] の列にする．
+//!    [`Expr`] 以降は文字列と位置（ [`Span`] ）を自前でもつので，
+//!    [`SourceMap`] を捨てた後も使え，複製や serde での保存もできる．
+//!    [`Span`] はファイル内のバイト位置で，行と列はエラーを表示するときに求める．
+//! 3. [`Document::from_source`] で，ID ごとに名前や説明，情報（ [`Field`] ）を集め，
+//!    反応（ [`Reaction`] ）の化合物を名前から探す．
+//! 4. [`Document::print`] で HTML を書き出す．
+//!
+//! 各段階で見つかった誤りは [`Diagnostics`] に溜まっていく．
+//! 途中で誤りがあっても最後まで処理できるので，
+//! 最後に [`Diagnostics::has_errors`] を見て出力を使うかどうかを決める．
+//!
+//! ```
+//! use converter::{Diagnostics, Document, Encoding, Page, SourceMap};
+//!
+//! let mut source_map = SourceMap::default();
+//! let mut diagnostics = Diagnostics::default();
+//! let file = converter::read_str(
+//!     "example",
+//!     "glucose [グルコース] +解糖系{ [グルコース]は 6 炭糖である． }",
+//!     &mut source_map,
+//! );
+//! let exprs = converter::parse(&mut source_map, &[file], Encoding::Utf8, &mut diagnostics);
+//! let document = Document::from_source(exprs, &mut diagnostics);
+//! let page = Page {
+//!     title: "例",
+//!     stylesheet: "style.css",
+//! };
+//! let mut html = Vec::new();
+//! document.print(&mut html, &page, &mut diagnostics).unwrap();
+//! assert!(!diagnostics.has_errors());
+//! assert!(String::from_utf8(html).unwrap().contains("<a href=\"#glucose\">"));
+//! ```
+
+pub mod diagnostic;
+pub mod document;
+pub mod escape;
+pub mod formula;
+pub mod metadata;
+pub mod reaction;
+pub mod read_files;
+pub mod search_dir;
+pub mod source;
+pub mod source_map;
+pub mod text;
+
+mod ignore;
+mod include;
+mod macros;
+mod normalize;
+mod suggest;
+#[cfg(test)]
+mod test_util;
+
+pub use diagnostic::{Diagnostic, Diagnostics, Level};
+pub use document::{Chapter, Content, Document, Item, Page};
+pub use formula::Formula;
+pub use metadata::{Field, FieldKind, Value};
+pub use reaction::{Participant, Reaction};
+pub use read_files::{read_files, read_str, Encoding, Part};
+pub use search_dir::{search_dir, Dir, Entry, IgnoreReason, Ignored};
+pub use source::{Expr, Source, Word};
+pub use source_map::{SourceMap, Span};
+pub use text::{Text, Token};
+
+use std::error::Error;
+use std::path::Path;
+
+/// dir 内の番号付きのファイルを UTF-8 として番号順に読み込み， source_map に追加する．
+/// [`search_dir()`] と [`read_files()`] を続けて呼ぶのと同じ．
+pub fn read_dir<P: AsRef<Path>>(
+    dir: P,
+    source_map: &mut SourceMap,
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<Part>, Box<dyn Error>> {
+    read_files(&search_dir(dir)?, Encoding::Utf8, source_map, diagnostics)
+}
+
+/// read_files() などで読み込んだ parts を，この順にパースして連結する．
+/// 章の区切りは [`Expr::BeginChapter`] と [`Expr::EndChapter`] になる．
+/// `\include{path}` は path のファイルを encoding で読んで，その中身に置き換える．
+/// `\def` で定義したマクロは，全てのファイルを読んだ後で展開する．
+/// 誤りがあった部分は diagnostics に記録して読み飛ばす．
+pub fn parse(
+    source_map: &mut SourceMap,
+    parts: &[Part],
+    encoding: Encoding,
+    diagnostics: &mut Diagnostics,
+) -> Vec<Expr> {
+    let mut ret = Vec::new();
+    for part in parts {
+        match part {
+            Part::File(file) => ret.extend(include::parse_file(
+                *file,
+                source_map,
+                encoding,
+                diagnostics,
+            )),
+            Part::BeginChapter(title) => ret.push(Expr::BeginChapter(title.clone())),
+            Part::EndChapter => ret.push(Expr::EndChapter),
+        }
+    }
+    macros::expand(ret, diagnostics)
+}
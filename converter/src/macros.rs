@@ -0,0 +1,443 @@
+// マクロ．
+//
+//     \def\nadh{NAD^+ → NADH}
+//     \def\dep#1{#1 依存}
+//
+// のように定義しておくと，説明や名前の中の \nadh や \dep{Mg^{2+}} が中身に置き換えられる．
+// 引数は #1 から #9 まで．呼び出しの直後に続く { } が順に引数になる．
+// 定義はどのファイルのどこに書いてもよく，全ファイルで共通．
+
+use super::diagnostic::{Diagnostic, Diagnostics};
+use super::include;
+use super::metadata::FieldKind;
+use super::reaction;
+use super::source::{Expr, Word};
+use super::source_map::Span;
+use super::suggest::suggest;
+use super::text::{Text, Token};
+use std::collections::HashMap;
+
+const TAG: &str = "def\\";
+
+// 展開の入れ子の深さの上限．
+// 自分自身を呼び出すマクロで止まらなくなるのを防ぐ
+const RECURSION_LIMIT: usize = 32;
+
+struct Macro {
+    params: usize,
+    body: Text,
+    span: Span, // 定義の位置
+}
+
+#[derive(thiserror::Error, Debug)]
+enum MacroError {
+    #[error("invalid macro definition `\\{0}`")]
+    InvalidDefinition(String, Span),
+    #[error("macro `\\{0}` is defined more than once")]
+    DuplicateDefinition(String, Span, Span), // 最初の定義と，2 つ目の定義
+    #[error("macro `\\{0}` has no parameter `#{1}`")]
+    UnknownParameter(String, usize, Span),
+    #[error("undefined macro `\\{0}`")]
+    Undefined(String, Span, Vec<String>),
+    #[error("`\\{0}` must be written outside of `{{ }}`")]
+    NotTopLevel(String, Span),
+    #[error("macro `\\{0}` takes {1} argument(s) but {2} were given")]
+    MissingArguments(String, usize, usize, Span),
+    #[error("`#{0}` outside of a macro definition")]
+    ParamOutsideMacro(usize, Span),
+    #[error("recursion limit reached while expanding `\\{0}`")]
+    RecursionLimit(String, Span, Span),
+}
+
+impl From<MacroError> for Diagnostic {
+    fn from(err: MacroError) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&err);
+        match err {
+            MacroError::InvalidDefinition(_, span) => diagnostic
+                .label(span, "invalid definition")
+                .help("write `\\def\\name{...}`, or `\\def\\name#1#2{...}` with parameters"),
+            MacroError::DuplicateDefinition(_, first, second) => diagnostic
+                .label(second, "defined again here")
+                .label(first, "first defined here"),
+            MacroError::UnknownParameter(_, _, span) => {
+                diagnostic.label(span, "not declared after the macro name")
+            }
+            MacroError::Undefined(_, span, suggestions) => {
+                let diagnostic = diagnostic.label(span, "not defined");
+                match suggestions.first() {
+                    Some(name) => diagnostic.help(format!("did you mean `\\{}`?", name)),
+                    None => diagnostic.help("write `\\\\` to get a literal backslash"),
+                }
+            }
+            MacroError::NotTopLevel(_, span) => diagnostic
+                .label(span, "written inside brackets")
+                .help("move it out of the description or name it is written in"),
+            MacroError::MissingArguments(_, _, _, span) => {
+                diagnostic.label(span, "write the arguments in `{ }` right after this")
+            }
+            MacroError::ParamOutsideMacro(_, span) => diagnostic
+                .label(span, "parameter used here")
+                .help("write `\\#` to get a literal `#`"),
+            MacroError::RecursionLimit(_, call, definition) => diagnostic
+                .label(call, "called here")
+                .label(definition, "defined here")
+                .help(format!(
+                    "macros can be nested at most {} levels deep",
+                    RECURSION_LIMIT
+                )),
+        }
+    }
+}
+
+// 上限を超えて展開しようとした
+struct LimitReached;
+
+/// \def の定義を集めて取り除き，残りの Text の中のマクロを展開する．
+/// 誤りは diagnostics に記録し，展開できなかったマクロは書かれたままの形で残す
+pub fn expand(exprs: Vec<Expr>, diagnostics: &mut Diagnostics) -> Vec<Expr> {
+    let mut macros = HashMap::<String, Macro>::new();
+    let mut rest = Vec::new();
+    for expr in exprs {
+        match expr {
+            Expr::Head(tag, body) if tag.value.starts_with(TAG) => {
+                if let Some((name, definition)) = define(&tag, body, diagnostics) {
+                    match macros.get(&name) {
+                        Some(prev) => diagnostics.error(MacroError::DuplicateDefinition(
+                            name,
+                            prev.span,
+                            definition.span,
+                        )),
+                        None => {
+                            macros.insert(name, definition);
+                        }
+                    }
+                }
+            }
+            expr => rest.push(expr),
+        }
+    }
+    let mut expander = Expander {
+        macros: &macros,
+        diagnostics,
+    };
+    rest.into_iter()
+        .map(|expr| match expr {
+            Expr::Name(text) => Expr::Name(expander.text(text)),
+            Expr::Head(tag, text) => Expr::Head(tag, expander.text(text)),
+            Expr::Desc(group, text) => Expr::Desc(group, expander.text(text)),
+            expr => expr,
+        })
+        .collect()
+}
+
+// "def\name#1#2" という tag から，名前と引数の数を読む
+fn define(tag: &Word, body: Text, diagnostics: &mut Diagnostics) -> Option<(String, Macro)> {
+    let rest = &tag.value[TAG.len()..];
+    let name_len = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    let (name, mut params_str) = rest.split_at(name_len);
+    let mut params = 0;
+    while let Some(rest) = params_str.strip_prefix(&format!("#{}", params + 1)) {
+        params += 1;
+        params_str = rest;
+    }
+    if name.is_empty() || !params_str.trim().is_empty() || params > 9 {
+        diagnostics.error(MacroError::InvalidDefinition(
+            tag.value[TAG.len()..].to_string(),
+            tag.span,
+        ));
+        return None;
+    }
+    let mut valid = true;
+    check_params(name, &body, params, &mut valid, diagnostics);
+    if !valid {
+        return None;
+    }
+    let span = tag.span.to(body.span);
+    Some((name.to_string(), Macro { params, body, span }))
+}
+
+// 定義の中で，宣言していない番号の引数を使っていないか
+fn check_params(
+    name: &str,
+    text: &Text,
+    params: usize,
+    valid: &mut bool,
+    diagnostics: &mut Diagnostics,
+) {
+    for token in &text.text {
+        match token {
+            Token::Param(n, span) if *n > params => {
+                diagnostics.error(MacroError::UnknownParameter(name.to_string(), *n, *span));
+                *valid = false;
+            }
+            Token::Block(text) | Token::Link(text) | Token::Paren(text) => {
+                check_params(name, text, params, valid, diagnostics)
+            }
+            _ => {}
+        }
+    }
+}
+
+struct Expander<'a, 'b> {
+    macros: &'a HashMap<String, Macro>,
+    diagnostics: &'b mut Diagnostics,
+}
+
+impl<'a, 'b> Expander<'a, 'b> {
+    // source に直接書かれた Text を展開する
+    fn text(&mut self, text: Text) -> Text {
+        let tokens = self.tokens(text.text, 0).unwrap_or_default();
+        Text {
+            text: tokens,
+            span: text.span,
+        }
+    }
+
+    // depth はマクロの展開の入れ子の深さ．
+    // 0 のとき（ source に直接書かれた部分）だけ，上限に達したことを報告する
+    fn tokens(&mut self, tokens: Vec<Token>, depth: usize) -> Result<Vec<Token>, LimitReached> {
+        let mut ret = Vec::new();
+        let mut iter = tokens.into_iter().peekable();
+        while let Some(token) = iter.next() {
+            match token {
+                Token::Macro(name, span) => {
+                    let definition = match self.macros.get(&name) {
+                        Some(definition) => definition,
+                        None if is_directive(&name) => {
+                            // \def\name の \name は未定義のマクロとして報告しない
+                            let defined = match name.as_str() {
+                                "def" => iter.next_if(|token| matches!(token, Token::Macro(..))),
+                                _ => None,
+                            };
+                            self.diagnostics
+                                .error(MacroError::NotTopLevel(name.clone(), span));
+                            ret.push(Token::Macro(name, span));
+                            ret.extend(defined);
+                            continue;
+                        }
+                        None => {
+                            let suggestions = suggest(
+                                &name,
+                                self.macros.keys().map(|name| (name.clone(), name.clone())),
+                            );
+                            self.diagnostics.error(MacroError::Undefined(
+                                name.clone(),
+                                span,
+                                suggestions,
+                            ));
+                            ret.push(Token::Macro(name, span));
+                            continue;
+                        }
+                    };
+                    // 直後の { } を引数として読む
+                    let mut args = Vec::new();
+                    while args.len() < definition.params {
+                        match iter.next_if(|token| matches!(token, Token::Block(_))) {
+                            Some(Token::Block(arg)) => args.push(arg),
+                            _ => break,
+                        }
+                    }
+                    if args.len() < definition.params {
+                        self.diagnostics.error(MacroError::MissingArguments(
+                            name.clone(),
+                            definition.params,
+                            args.len(),
+                            span,
+                        ));
+                        push_unexpanded(&mut ret, name, span, args);
+                        continue;
+                    }
+                    if depth >= RECURSION_LIMIT {
+                        return Err(LimitReached);
+                    }
+                    let body = substitute(&definition.body.text, &args);
+                    match self.tokens(body, depth + 1) {
+                        Ok(expanded) => push_expanded(&mut ret, expanded, span),
+                        Err(LimitReached) if depth == 0 => {
+                            self.diagnostics.error(MacroError::RecursionLimit(
+                                name.clone(),
+                                span,
+                                definition.span,
+                            ));
+                            push_unexpanded(&mut ret, name, span, args);
+                        }
+                        Err(LimitReached) => return Err(LimitReached),
+                    }
+                }
+                Token::Param(n, span) => {
+                    self.diagnostics
+                        .error(MacroError::ParamOutsideMacro(n, span));
+                    ret.push(Token::Param(n, span));
+                }
+                Token::Block(text) => ret.push(Token::Block(self.nested(text, depth)?)),
+                Token::Link(text) => ret.push(Token::Link(self.nested(text, depth)?)),
+                Token::Paren(text) => ret.push(Token::Paren(self.nested(text, depth)?)),
+                token => ret.push(token),
+            }
+        }
+        Ok(ret)
+    }
+
+    fn nested(&mut self, text: Text, depth: usize) -> Result<Text, LimitReached> {
+        Ok(Text {
+            text: self.tokens(text.text, depth)?,
+            span: text.span,
+        })
+    }
+}
+
+// \include や \def などは { } の外に書く命令で，マクロではない．
+// { } の中に書くとマクロの呼び出しとして読まれてしまうので，別のエラーにする
+fn is_directive(name: &str) -> bool {
+    name == include::TAG
+        || name == "def"
+        || name == reaction::TAG
+        || FieldKind::from_tag(name).is_some()
+}
+
+// 定義の中の #1 などを引数に置き換える
+fn substitute(body: &[Token], args: &[Text]) -> Vec<Token> {
+    let mut ret = Vec::new();
+    for token in body {
+        match token {
+            Token::Param(n, span) => push_expanded(&mut ret, args[n - 1].text.clone(), *span),
+            Token::Block(text) => ret.push(Token::Block(substitute_text(text, args))),
+            Token::Link(text) => ret.push(Token::Link(substitute_text(text, args))),
+            Token::Paren(text) => ret.push(Token::Paren(substitute_text(text, args))),
+            token => ret.push(token.clone()),
+        }
+    }
+    ret
+}
+
+fn substitute_text(text: &Text, args: &[Text]) -> Text {
+    Text {
+        text: substitute(&text.text, args),
+        span: text.span,
+    }
+}
+
+// 展開した結果を ret に加える．
+// 直前が ^ や _ のときは，展開した結果全体が上付き/下付きになるよう { } でくくる
+fn push_expanded(ret: &mut Vec<Token>, expanded: Vec<Token>, span: Span) {
+    if matches!(ret.last(), Some(Token::Sup(_) | Token::Sub(_))) {
+        ret.push(Token::Block(Text {
+            text: expanded,
+            span,
+        }));
+    } else {
+        ret.extend(expanded);
+    }
+}
+
+// 展開できなかった呼び出しを，読んだ引数ごと書かれたままの形で ret に戻す
+fn push_unexpanded(ret: &mut Vec<Token>, name: String, span: Span, args: Vec<Text>) {
+    ret.push(Token::Macro(name, span));
+    ret.extend(args.into_iter().map(Token::Block));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::parse_str;
+    use crate::{Expr, Text, Token};
+
+    // source を読んでマクロを展開し，最後の説明とエラーのメッセージを返す
+    fn expand(source: &str) -> (Text, Vec<String>) {
+        let (exprs, diagnostics) = parse_str(source);
+        let desc = exprs.into_iter().rev().find_map(|expr| match expr {
+            Expr::Desc(_, text) => Some(text),
+            _ => None,
+        });
+        let errors = diagnostics
+            .iter()
+            .map(|(_, diagnostic)| diagnostic.message().to_string())
+            .collect();
+        (desc.unwrap(), errors)
+    }
+
+    #[test]
+    fn expand_macros() {
+        // (source, 展開した結果)
+        let cases = [
+            ("\\def\\nadh{NAD^+ → NADH} x [X] +{\\nadh}", "NAD^+ → NADH"),
+            (
+                "\\def\\dep#1{#1 依存} x [X] +{\\dep{Mg^{2+}}}",
+                "Mg^{2+} 依存",
+            ),
+            ("\\def\\pair#1#2{#2 と #1} x [X] +{\\pair{A}{B}}", "B と A"),
+            // 入れ子の呼び出しと，[ ] の中の呼び出し
+            ("\\def\\a{A} \\def\\b#1{[#1\\a]} x [X] +{\\b{\\a}}", "[AA]"),
+            // ^ や _ の直後では，展開した結果全体を { } でくくる
+            ("\\def\\nadh{NAD^+} x [X] +{x^\\nadh}", "x^{NAD^+}"),
+            // 定義は使う場所より後にあってもよい
+            ("x [X] +{\\a} \\def\\a{A}", "A"),
+        ];
+        for (source, expected) in cases {
+            let (text, errors) = expand(source);
+            assert!(errors.is_empty(), "{}: {:?}", source, errors);
+            assert_eq!(text.to_string(), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn wrap_after_sup() {
+        let (text, _) = expand("\\def\\nadh{NAD^+} x [X] +{x^\\nadh}");
+        assert!(matches!(
+            &text.text[..],
+            [Token::Str(..), Token::Sup(_), Token::Block(_)]
+        ));
+    }
+
+    #[test]
+    fn invalid_macros() {
+        // (source, 展開した結果, エラーのメッセージ)
+        let cases = [
+            // 展開できなかった呼び出しは書かれたまま残す
+            (
+                "\\def\\foo#1#2{#1#2} x [X] +{\\foo{a} b}",
+                "\\foo{a} b",
+                "macro `\\foo` takes 2 argument(s) but 1 were given",
+            ),
+            (
+                "\\def\\bar{\\bar} x [X] +{\\bar}",
+                "\\bar",
+                "recursion limit reached while expanding `\\bar`",
+            ),
+            ("\\def\\a{A} x [X] +{\\b}", "\\b", "undefined macro `\\b`"),
+            (
+                "\\def\\a{A} \\def\\a{B} x [X] +{\\a}",
+                "A",
+                "macro `\\a` is defined more than once",
+            ),
+            (
+                "\\def\\a#1{#2} x [X] +{x}",
+                "x",
+                "macro `\\a` has no parameter `#2`",
+            ),
+            ("x [X] +{#1}", "#1", "`#1` outside of a macro definition"),
+            // { } の外に書く命令
+            (
+                "x [X] +{\\include{a}}",
+                "\\include{a}",
+                "`\\include` must be written outside of `{ }`",
+            ),
+            (
+                "x [X] +{\\def\\a{A}}",
+                "\\def\\a{A}",
+                "`\\def` must be written outside of `{ }`",
+            ),
+            (
+                "x [X] +{\\reaction{[A] → [B]}}",
+                "\\reaction{[A] → [B]}",
+                "`\\reaction` must be written outside of `{ }`",
+            ),
+        ];
+        for (source, expected, error) in cases {
+            let (text, errors) = expand(source);
+            assert_eq!(text.to_string(), expected, "{}", source);
+            assert_eq!(errors, [error], "{}", source);
+        }
+    }
+}
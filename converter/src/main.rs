@@ -1,74 +1,177 @@
-mod char;
+mod args;
+use args::{Args, Command, Options};
 
-mod search_dir;
-use search_dir::search_dir;
+use converter::{read_files, search_dir, Diagnostics, Document, Page, SourceMap};
 
-mod read_files;
-use read_files::read_files;
+mod output;
+mod serve;
+mod watch;
 
-mod source;
-mod text;
-use source::Source;
-
-mod document;
-use document::Document;
+use std::error::Error;
+use std::path::{Path, PathBuf};
 
 fn main() {
-    // source ディレクトリ内のファイルを走査
-    match search_dir("source") {
-        // ファイルを番号順に連結して一つの文字列にする
-        Ok(files) => match read_files(&files) {
-            Ok(text) => {
-                // Source に渡してからパースする
-                // （Source は文字列以外にイテレータも持つ）
-                let mut source = Source::from(&text);
-                match source.parse() {
-                    Ok(source) => {
-                        // Document に変換
-                        // "glucose [グルコース]" と書いてあったときに
-                        // "glucose" と "グルコース" を紐付けるような作業は
-                        // ここで行われる
-                        match Document::from_source(&source) {
-                            Ok(document) => {
-                                match std::fs::File::create("index.html") {
-                                    Ok(out) => {
-                                        // index.html に書き出し．
-                                        // 文中の[グルコース]をリンクにしたり
-                                        // ^ や _ を <sup> や <sub> に変えたりする作業は
-                                        // ここで行われる
-                                        let mut buf = std::io::BufWriter::new(out);
-                                        match document.print(&mut buf) {
-                                            Ok(()) => {
-                                                println!("output written to index.html");
-                                            }
-                                            Err(err) => {
-                                                // print error でも途中まで書き出されてしまう……
-                                                // （直すべき？）
-                                                eprintln!("print error: {}", err);
-                                            }
-                                        }
-                                    }
-                                    Err(err) => {
-                                        eprintln!("failed to open output file: {}", err);
-                                    }
-                                }
-                            }
-                            Err(err) => {
-                                eprintln!("compile error: {}", err);
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        eprintln!("parse error: {}", err);
-                    }
-                }
-            }
-            Err(err) => {
-                eprintln!("error while reading files: {}", err);
+    let args = Args::parse_checked();
+    let options = &args.options;
+    if let Some(Command::Serve { address }) = &args.command {
+        let err = serve::serve(address, options);
+        eprintln!("error: failed to listen on {}: {}", address, err);
+        std::process::exit(1);
+    }
+    if let Some(Command::Check { deny_warnings }) = &args.command {
+        let succeeded = check(options, *deny_warnings);
+        std::process::exit(if succeeded { 0 } else { 1 });
+    }
+    if args.list_files {
+        let succeeded = list_files(options);
+        std::process::exit(if succeeded { 0 } else { 1 });
+    }
+    let (succeeded, read) = build(options);
+    if args.watch {
+        // 以後，source ディレクトリ内のファイルや \include したファイルが変わるたびに変換し直す
+        if !options.quiet {
+            eprintln!("watching {} for changes", options.source.display());
+        }
+        watch::watch(&options.source, read, || {
+            if !options.quiet {
+                eprintln!("change detected, rebuilding");
             }
-        },
+            build(options).1
+        });
+    }
+    if !succeeded {
+        std::process::exit(1);
+    }
+}
+
+// source ディレクトリ内のファイルを，連結する順に番号を付けて出力する．
+// 続けて，読まないファイルとその理由を出力する．
+fn list_files(options: &Options) -> bool {
+    let dir = match search_dir(&options.source) {
+        Ok(dir) => dir,
         Err(err) => {
-            eprintln!("error while searching directory: {}", err);
+            eprintln!("error: error while searching directory: {}", err);
+            return false;
+        }
+    };
+    let files = dir.files();
+    let width = files.len().to_string().len();
+    for (i, path) in files.iter().enumerate() {
+        println!("{:>width$} {}", i + 1, path.display(), width = width);
+    }
+    for ignored in dir.ignored_files() {
+        println!(
+            "{:>width$} {} ({})",
+            "-",
+            ignored.path.display(),
+            ignored.reason,
+            width = width
+        );
+    }
+    true
+}
+
+// 1 回分の変換を行い，エラーや警告を出力する．
+// エラーが無ければ true を，あわせて読み込んだファイル（ \include したものも含む）を返す．
+fn build(options: &Options) -> (bool, Vec<PathBuf>) {
+    let mut diagnostics = Diagnostics::default();
+    let mut source_map = SourceMap::default();
+    // 続行できないエラー（ファイルが読めないなど）のときだけ Err が返る．
+    // source の記述の誤りは diagnostics に溜まる．
+    if let Err(err) = run(options, &mut source_map, &mut diagnostics) {
+        diagnostics.error(err);
+    }
+    diagnostics.print(&source_map);
+    let read = source_map.paths().map(Path::to_path_buf).collect();
+    if diagnostics.has_errors() {
+        if !options.quiet {
+            eprintln!(
+                "{} not updated due to errors",
+                options.output_file().display()
+            );
+        }
+        return (false, read);
+    }
+    (true, read)
+}
+
+// 変換はするが，出力は書き出さずにエラーや警告だけを出力する．
+// deny_warnings のときは警告もエラーと同様に扱う．
+// 問題が無ければ true を返す．
+fn check(options: &Options, deny_warnings: bool) -> bool {
+    let mut diagnostics = Diagnostics::default();
+    let mut source_map = SourceMap::default();
+    if let Err(err) = render(options, &mut source_map, &mut diagnostics) {
+        diagnostics.error(err);
+    }
+    diagnostics.print(&source_map);
+    let failed = diagnostics.has_errors() || (deny_warnings && diagnostics.warning_count() > 0);
+    let succeeded = !failed;
+    if succeeded && !options.quiet {
+        println!("no problems found in {}", options.source.display());
+    }
+    succeeded
+}
+
+fn run(
+    options: &Options,
+    source_map: &mut SourceMap,
+    diagnostics: &mut Diagnostics,
+) -> Result<(), Box<dyn Error>> {
+    // いったんメモリ上に書き出し，エラーが無かったときだけファイルに書き込む．
+    // エラーがあったときや書き込みに失敗したときは，前回の出力がそのまま残る．
+    let buf = render(options, source_map, diagnostics)?;
+    if diagnostics.has_errors() {
+        return Ok(());
+    }
+    let output = options.output_file();
+    output::write_atomically(&output, &buf)
+        .map_err(|err| format!("failed to write output file: {}", err))?;
+    if !options.quiet {
+        println!("output written to {}", output.display());
+    }
+    Ok(())
+}
+
+// source ディレクトリを読み込んで変換し，出力する HTML を返す．
+fn render(
+    options: &Options,
+    source_map: &mut SourceMap,
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    // source ディレクトリ内のファイルを走査
+    let files = search_dir(&options.source)
+        .map_err(|err| format!("error while searching directory: {}", err))?;
+    if options.verbose {
+        for path in files.files() {
+            eprintln!("reading {}", path.display());
         }
+        for ignored in files.ignored_files() {
+            eprintln!("ignoring {} ({})", ignored.path.display(), ignored.reason);
+        }
+    }
+    // ファイルを番号順に読み込む
+    let read = read_files(&files, options.encoding, source_map, diagnostics)
+        .map_err(|err| format!("error while reading files: {}", err))?;
+    // パースする
+    let source = converter::parse(source_map, &read, options.encoding, diagnostics);
+    // Document に変換
+    // "glucose [グルコース]" と書いてあったときに
+    // "glucose" と "グルコース" を紐付けるような作業は
+    // ここで行われる
+    let document = Document::from_source(source, diagnostics);
+    if options.verbose {
+        eprintln!("{} items found", document.items.len());
     }
+    // index.html に書き出し．
+    // 文中の[グルコース]をリンクにしたり
+    // ^ や _ を <sup> や <sub> に変えたりする作業は
+    // ここで行われる
+    let page = Page {
+        title: &options.title,
+        stylesheet: &options.stylesheet,
+    };
+    let mut buf = Vec::new();
+    document.print(&mut buf, &page, diagnostics)?;
+    Ok(buf)
 }
@@ -0,0 +1,369 @@
+// 化合物に付ける，決まった形の情報．
+//
+//     glucose [グルコース]
+//     \formula{C_6H_{12}O_6} \carbons{6} \kegg{C00031} \synonym{ブドウ糖}
+//
+// のように，ID の後に書くとその化合物の情報になる．
+// 説明とは別に，見出しの下の表にまとめて出力する．
+
+use super::diagnostic::{Diagnostic, Diagnostics};
+use super::source::Word;
+use super::source_map::Span;
+use super::text::{Text, Token};
+use serde::{Deserialize, Serialize};
+
+/// 情報の項目．出力するときはこの順に並べる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum FieldKind {
+    Formula,
+    Charge,
+    Carbons,
+    Cas,
+    Kegg,
+    Chebi,
+    Synonym,
+}
+
+/// 項目の値．書式の確認を済ませたもの
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Value {
+    Text(Text),   // 分子式と別名．上付き/下付きなどを使える
+    Integer(i64), // 電荷と炭素数
+    Id(String),   // データベースの ID
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Field {
+    pub kind: FieldKind,
+    pub value: Value,
+    pub span: Span, // \formula から閉じ括弧まで
+}
+
+impl FieldKind {
+    /// `\formula{ }` などのタグ名から項目を決める．情報の項目でなければ None
+    pub fn from_tag(tag: &str) -> Option<FieldKind> {
+        match tag {
+            "formula" => Some(FieldKind::Formula),
+            "charge" => Some(FieldKind::Charge),
+            "carbons" => Some(FieldKind::Carbons),
+            "cas" => Some(FieldKind::Cas),
+            "kegg" => Some(FieldKind::Kegg),
+            "chebi" => Some(FieldKind::Chebi),
+            "synonym" => Some(FieldKind::Synonym),
+            _ => None,
+        }
+    }
+
+    pub fn tag(self) -> &'static str {
+        match self {
+            FieldKind::Formula => "formula",
+            FieldKind::Charge => "charge",
+            FieldKind::Carbons => "carbons",
+            FieldKind::Cas => "cas",
+            FieldKind::Kegg => "kegg",
+            FieldKind::Chebi => "chebi",
+            FieldKind::Synonym => "synonym",
+        }
+    }
+
+    /// 表の見出し
+    pub fn label(self) -> &'static str {
+        match self {
+            FieldKind::Formula => "分子式",
+            FieldKind::Charge => "電荷",
+            FieldKind::Carbons => "炭素数",
+            FieldKind::Cas => "CAS 番号",
+            FieldKind::Kegg => "KEGG",
+            FieldKind::Chebi => "ChEBI",
+            FieldKind::Synonym => "別名",
+        }
+    }
+
+    /// 1 つの化合物に何度も書けるか
+    pub fn repeatable(self) -> bool {
+        self == FieldKind::Synonym
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum FieldError {
+    #[error("`\\{0}` is empty")]
+    Empty(&'static str, Span),
+    #[error("links and raw HTML are not allowed in `\\{0}`")]
+    NotPlain(&'static str, Span),
+    #[error("`{0}` is not a valid charge")]
+    InvalidCharge(String, Span),
+    #[error("`{0}` is not a valid number of carbons")]
+    InvalidCarbons(String, Span),
+    #[error("`{0}` is not a valid CAS registry number")]
+    InvalidCas(String, Span),
+    #[error("check digit of CAS registry number `{0}` should be {1}")]
+    CasCheckDigit(String, u32, Span),
+    #[error("`{0}` is not a valid KEGG ID")]
+    InvalidKegg(String, Span),
+    #[error("`{0}` is not a valid ChEBI ID")]
+    InvalidChebi(String, Span),
+}
+
+impl From<FieldError> for Diagnostic {
+    fn from(err: FieldError) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&err);
+        match err {
+            FieldError::Empty(_, span) => diagnostic.label(span, "write the value in `{ }`"),
+            FieldError::NotPlain(_, span) => diagnostic.label(span, "not allowed here"),
+            FieldError::InvalidCharge(_, span) => diagnostic
+                .label(span, "invalid charge")
+                .help("write an integer such as `0`, `-1` or `2+`"),
+            FieldError::InvalidCarbons(_, span) => diagnostic
+                .label(span, "invalid number")
+                .help("write a non-negative integer such as `6`"),
+            FieldError::InvalidCas(_, span) => diagnostic
+                .label(span, "invalid CAS registry number")
+                .help("write it in the form `50-99-7`"),
+            FieldError::CasCheckDigit(_, _, span) => {
+                diagnostic.label(span, "check digit does not match")
+            }
+            FieldError::InvalidKegg(_, span) => diagnostic
+                .label(span, "invalid KEGG ID")
+                .help("write a compound, drug or glycan ID such as `C00031`"),
+            FieldError::InvalidChebi(_, span) => diagnostic
+                .label(span, "invalid ChEBI ID")
+                .help("write it in the form `CHEBI:4167` or `4167`"),
+        }
+    }
+}
+
+impl Field {
+    /// tag と { } の中身から項目を作る．
+    /// 書式が正しくなければ diagnostics に記録して None を返す
+    pub(crate) fn parse(
+        kind: FieldKind,
+        tag: &Word,
+        text: Text,
+        diagnostics: &mut Diagnostics,
+    ) -> Option<Field> {
+        let span = tag.span.to(text.span);
+        let s = text.to_string().trim().to_string();
+        if s.is_empty() {
+            diagnostics.error(FieldError::Empty(kind.tag(), text.span));
+            return None;
+        }
+        let value = match kind {
+            FieldKind::Formula | FieldKind::Synonym => {
+                // 分子式の中にリンクや HTML があると表の中が崩れる
+                if kind == FieldKind::Formula {
+                    if let Some(span) = find_not_plain(&text) {
+                        diagnostics.error(FieldError::NotPlain(kind.tag(), span));
+                        return None;
+                    }
+                }
+                Value::Text(text)
+            }
+            FieldKind::Charge => match parse_charge(&s) {
+                Some(charge) => Value::Integer(charge),
+                None => {
+                    diagnostics.error(FieldError::InvalidCharge(s, text.span));
+                    return None;
+                }
+            },
+            FieldKind::Carbons => match s.parse::<u32>() {
+                Ok(carbons) if s.chars().all(|c| c.is_ascii_digit()) => {
+                    Value::Integer(carbons.into())
+                }
+                _ => {
+                    diagnostics.error(FieldError::InvalidCarbons(s, text.span));
+                    return None;
+                }
+            },
+            FieldKind::Cas => match cas_check_digit(&s) {
+                Some((expected, actual)) if expected == actual => Value::Id(s),
+                Some((expected, _)) => {
+                    diagnostics.error(FieldError::CasCheckDigit(s, expected, text.span));
+                    return None;
+                }
+                None => {
+                    diagnostics.error(FieldError::InvalidCas(s, text.span));
+                    return None;
+                }
+            },
+            FieldKind::Kegg => {
+                let valid = s.len() == 6
+                    && s.starts_with(['C', 'D', 'G'])
+                    && s[1..].chars().all(|c| c.is_ascii_digit());
+                if !valid {
+                    diagnostics.error(FieldError::InvalidKegg(s, text.span));
+                    return None;
+                }
+                Value::Id(s)
+            }
+            FieldKind::Chebi => {
+                // 数字だけを持っておき，出力するときに CHEBI: を付ける
+                let number = s.strip_prefix("CHEBI:").unwrap_or(&s);
+                if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+                    diagnostics.error(FieldError::InvalidChebi(s, text.span));
+                    return None;
+                }
+                Value::Id(number.to_string())
+            }
+        };
+        Some(Field { kind, value, span })
+    }
+
+    /// 同じ項目が 2 度書かれたとき，同じ値かどうかを比べる
+    pub fn same_value(&self, other: &Field) -> bool {
+        match (&self.value, &other.value) {
+            (Value::Text(a), Value::Text(b)) => a.to_string() == b.to_string(),
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Id(a), Value::Id(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// 値をリンクにするときの URL
+    pub fn url(&self) -> Option<String> {
+        match (&self.kind, &self.value) {
+            (FieldKind::Cas, Value::Id(id)) => Some(format!(
+                "https://commonchemistry.cas.org/detail?cas_rn={}",
+                id
+            )),
+            (FieldKind::Kegg, Value::Id(id)) => Some(format!("https://www.kegg.jp/entry/{}", id)),
+            (FieldKind::Chebi, Value::Id(id)) => Some(format!(
+                "https://www.ebi.ac.uk/chebi/searchId.do?chebiId=CHEBI:{}",
+                id
+            )),
+            _ => None,
+        }
+    }
+}
+
+// 値を Text のまま出力しないものについて，表に書く文字列
+impl std::fmt::Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.kind, &self.value) {
+            (_, Value::Text(text)) => write!(f, "{}", text),
+            (FieldKind::Charge, Value::Integer(0)) => write!(f, "0"),
+            (FieldKind::Charge, Value::Integer(charge)) => write!(f, "{:+}", charge),
+            (_, Value::Integer(n)) => write!(f, "{}", n),
+            (FieldKind::Chebi, Value::Id(id)) => write!(f, "CHEBI:{}", id),
+            (_, Value::Id(id)) => write!(f, "{}", id),
+        }
+    }
+}
+
+fn find_not_plain(text: &Text) -> Option<Span> {
+    text.text.iter().find_map(|token| match token {
+        Token::Link(text) => Some(text.span),
+        Token::Raw(_, span) => Some(*span),
+        Token::Block(text) | Token::Paren(text) => find_not_plain(text),
+        _ => None,
+    })
+}
+
+// 0 ， -1 ， +2 ， 2+ ， 3- ， + ， - のような書き方を受け付ける．
+// 記号はマイナス記号 − でもよい
+pub(crate) fn parse_charge(s: &str) -> Option<i64> {
+    let s = s.replace('−', "-");
+    let (sign, digits) = if let Some(rest) = s.strip_prefix('+') {
+        (1, rest)
+    } else if let Some(rest) = s.strip_prefix('-') {
+        (-1, rest)
+    } else if let Some(rest) = s.strip_suffix('+') {
+        (1, rest)
+    } else if let Some(rest) = s.strip_suffix('-') {
+        (-1, rest)
+    } else {
+        (1, s.as_str())
+    };
+    if digits.is_empty() {
+        // 記号だけなら ±1
+        return Some(sign);
+    }
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse::<i64>().ok().map(|n| sign * n)
+}
+
+// CAS 登録番号は 2〜7 桁，2 桁，1 桁の数字をハイフンでつないだもの．
+// 最後の 1 桁はチェックディジットで，それ以外の数字に右から 1, 2, 3, … を掛けた和の 1 の位．
+// 書式が正しければ，（あるべきチェックディジット，書かれたチェックディジット）を返す
+fn cas_check_digit(s: &str) -> Option<(u32, u32)> {
+    let parts: Vec<&str> = s.split('-').collect();
+    match parts[..] {
+        [first, second, check]
+            if (2..=7).contains(&first.len())
+                && second.len() == 2
+                && check.len() == 1
+                && parts
+                    .iter()
+                    .all(|part| part.chars().all(|c| c.is_ascii_digit())) =>
+        {
+            let sum: u32 = first
+                .chars()
+                .chain(second.chars())
+                .rev()
+                .enumerate()
+                .map(|(i, c)| (i as u32 + 1) * c.to_digit(10).unwrap())
+                .sum();
+            Some((sum % 10, check.parse().unwrap()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cas_check_digit, parse_charge};
+
+    #[test]
+    fn charge() {
+        let cases = [
+            ("0", Some(0)),
+            ("1", Some(1)),
+            ("-1", Some(-1)),
+            ("+2", Some(2)),
+            ("2+", Some(2)),
+            ("3-", Some(-3)),
+            ("3−", Some(-3)),
+            ("−1", Some(-1)),
+            ("+", Some(1)),
+            ("-", Some(-1)),
+            ("−", Some(-1)),
+            ("+-", None),
+            ("+2+", None),
+            ("2.5", None),
+            ("x", None),
+            ("99999999999999999999", None),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(parse_charge(s), expected, "{}", s);
+        }
+    }
+
+    #[test]
+    fn cas() {
+        // (CAS 登録番号, チェックディジットが合っているか)．書式が正しくなければ None
+        let cases = [
+            ("50-99-7", Some(true)),
+            ("50-99-8", Some(false)),
+            ("7732-18-5", Some(true)),
+            ("7440-44-0", Some(true)),
+            ("7732-18-4", Some(false)),
+            ("5-99-7", None),
+            ("12345678-99-7", None),
+            ("50-9-7", None),
+            ("50-99-77", None),
+            ("50-99", None),
+            ("50-99-x", None),
+            ("5O-99-7", None),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(
+                cas_check_digit(s).map(|(expected, actual)| expected == actual),
+                expected,
+                "{}",
+                s
+            );
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use unicode_normalization::UnicodeNormalization;
+
+// 名前を比較するときの正規化．
+// - NFKC で全角の英数字や記号を半角にする（ＡＴＰ → ATP ， ６ → 6 ）
+// - ハイフンに似た文字をハイフンにする
+// - 連続した空白（改行を含む）を 1 つの空白にし，前後の空白を取り除く
+// 行をまたいだリンク [グルコース
+// 6-リン酸] なども，これで [グルコース 6-リン酸] と一致する．
+pub fn normalize(s: &str) -> String {
+    let mut ret = String::new();
+    let mut space = false;
+    for c in s.nfkc() {
+        if c.is_whitespace() {
+            space = true;
+            continue;
+        }
+        if space && !ret.is_empty() {
+            ret.push(' ');
+        }
+        space = false;
+        ret.push(match c {
+            '\u{2010}' | '\u{2011}' | '\u{2012}' | '\u{2013}' | '\u{2212}' => '-',
+            c => c,
+        });
+    }
+    ret
+}
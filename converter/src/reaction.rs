@@ -0,0 +1,648 @@
+// 反応．
+//
+//     \reaction{
+//         [グルコース] + [ATP] → [グルコース 6-リン酸] + [ADP]
+//         enzyme: [ヘキソキナーゼ]
+//         cofactor: Mg^{2+}
+//         note: 解糖系の最初の段階
+//     }
+//
+// のように書く．キーの付かない 1 行が反応式で，化合物は [ ] でくくった名前で書き，
+// 前に係数を付けられる．矢印は → （ -> ）が不可逆， ⇄ （ <=> ）が可逆．
+// キーの付いた行は省略でき， cofactor: は何行でも書ける．
+// cofactor: に [ATP] → [ADP] のような反応式を書くと，共役する反応として釣り合いの確認に加える．
+// 反応はどこに書いてもよく，関わる化合物の記事に自動で並べられる．
+
+use super::diagnostic::{Diagnostic, Diagnostics};
+use super::document::Item;
+use super::formula::Formula;
+use super::source::Word;
+use super::source_map::Span;
+use super::text::{Text, Token};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+pub const TAG: &str = "reaction";
+
+const IRREVERSIBLE: &[&str] = &["->", "→"];
+const REVERSIBLE: &[&str] = &["<=>", "<->", "⇄", "⇌", "↔"];
+
+/// 反応式の左辺や右辺に現れる化合物 1 つ
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Participant {
+    pub coefficient: u64,
+    pub name: Text, // [ ] の中身
+    // 名前から探した化合物． Document::from_source で埋める
+    pub item: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reaction {
+    pub substrates: Vec<Participant>,
+    pub products: Vec<Participant>,
+    pub reversible: bool,
+    pub enzyme: Option<Text>,
+    pub cofactors: Vec<Text>,
+    // cofactor: に反応式として書かれた，共役する反応の左辺と右辺
+    pub coupled_substrates: Vec<Participant>,
+    pub coupled_products: Vec<Participant>,
+    pub note: Option<Text>,
+    pub span: Span, // \reaction から閉じ括弧まで
+}
+
+#[derive(thiserror::Error, Debug)]
+enum ReactionError {
+    #[error("reaction has no equation")]
+    NoEquation(Span),
+    #[error("reaction has more than one equation")]
+    MultipleEquations(Span, Span), // 1 つ目と 2 つ目
+    #[error("equation has no arrow")]
+    NoArrow(Span),
+    #[error("equation has more than one arrow")]
+    MultipleArrows(Span),
+    #[error("no compounds on the {0} side of the equation")]
+    EmptySide(&'static str, Span),
+    #[error("unexpected `{0}` in equation")]
+    Unexpected(String, Span),
+    #[error("`{0}:` is given more than once")]
+    DuplicateKey(&'static str, Span),
+}
+
+#[derive(thiserror::Error, Debug)]
+enum ReactionWarning {
+    #[error("reaction is not balanced")]
+    Unbalanced(Span, Vec<String>), // 釣り合わない元素や電荷ごとの説明
+}
+
+impl From<ReactionWarning> for Diagnostic {
+    fn from(warning: ReactionWarning) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&warning);
+        match warning {
+            ReactionWarning::Unbalanced(span, differences) => differences.into_iter().fold(
+                diagnostic.label(span, "in this reaction"),
+                |diagnostic, difference| diagnostic.help(difference),
+            ),
+        }
+    }
+}
+
+impl From<ReactionError> for Diagnostic {
+    fn from(err: ReactionError) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&err);
+        match err {
+            ReactionError::NoEquation(span) => diagnostic
+                .label(span, "in this reaction")
+                .help("write an equation like `[A] + [B] → [C]`"),
+            ReactionError::MultipleEquations(first, second) => diagnostic
+                .label(second, "another equation")
+                .label(first, "first equation")
+                .help("write each reaction in its own `\\reaction{ }`"),
+            ReactionError::NoArrow(span) => diagnostic
+                .label(span, "in this equation")
+                .help("use `→` (`->`) for irreversible or `⇄` (`<=>`) for reversible reactions"),
+            ReactionError::MultipleArrows(span) => diagnostic.label(span, "second arrow"),
+            ReactionError::EmptySide(_, span) => diagnostic.label(span, "next to this arrow"),
+            ReactionError::Unexpected(_, span) => diagnostic
+                .label(span, "unexpected")
+                .help("write compounds as `[name]`, optionally preceded by a coefficient"),
+            ReactionError::DuplicateKey(_, span) => diagnostic.label(span, "given again here"),
+        }
+    }
+}
+
+// 反応式の中の要素
+enum Term {
+    Coefficient(u64, Span),
+    Plus(Span),
+    Arrow(bool, &'static str, Span), // 可逆かどうかと，書かれた矢印
+    Compound(Text),
+}
+
+impl Reaction {
+    /// \reaction{ } の中身を読む．
+    /// 誤りがあれば diagnostics に記録して None を返す
+    pub(crate) fn parse(tag: &Word, text: Text, diagnostics: &mut Diagnostics) -> Option<Reaction> {
+        let span = tag.span.to(text.span);
+        let mut equation: Option<(Vec<Token>, Span)> = None;
+        let mut enzyme = None;
+        let mut cofactors = Vec::new();
+        let mut coupled_substrates = Vec::new();
+        let mut coupled_products = Vec::new();
+        let mut note = None;
+        let mut valid = true;
+        for mut line in lines(text.text) {
+            let line_span = line[0].span().to(line[line.len() - 1].span());
+            match split_key(&mut line) {
+                Some((key, key_span)) => {
+                    let value = Text {
+                        span: line
+                            .first()
+                            .map_or(key_span, |first| first.span().to(line_span)),
+                        text: line,
+                    };
+                    let slot = match key {
+                        "enzyme" => &mut enzyme,
+                        "note" => &mut note,
+                        _ => {
+                            // 反応式として読めなければ，ただの文として扱う
+                            if let Ok((substrates, products, _)) =
+                                parse_equation(value.text.clone(), value.span)
+                            {
+                                coupled_substrates.extend(substrates);
+                                coupled_products.extend(products);
+                            }
+                            cofactors.push(value);
+                            continue;
+                        }
+                    };
+                    if slot.is_some() {
+                        diagnostics.error(ReactionError::DuplicateKey(key, key_span));
+                        valid = false;
+                    }
+                    *slot = Some(value);
+                }
+                None => match &equation {
+                    Some((_, first)) => {
+                        diagnostics.error(ReactionError::MultipleEquations(*first, line_span));
+                        valid = false;
+                    }
+                    None => equation = Some((line, line_span)),
+                },
+            }
+        }
+        let (substrates, products, reversible) = match equation {
+            Some((line, line_span)) => match parse_equation(line, line_span) {
+                Ok(equation) => equation,
+                Err(err) => {
+                    diagnostics.error(err);
+                    return None;
+                }
+            },
+            None => {
+                diagnostics.error(ReactionError::NoEquation(span));
+                return None;
+            }
+        };
+        if !valid {
+            return None;
+        }
+        Some(Reaction {
+            substrates,
+            products,
+            reversible,
+            enzyme,
+            cofactors,
+            coupled_substrates,
+            coupled_products,
+            note,
+            span,
+        })
+    }
+
+    /// 共役する反応も含めて，反応に関わる化合物を全て
+    pub fn participants(&self) -> impl Iterator<Item = &Participant> {
+        self.substrates
+            .iter()
+            .chain(&self.products)
+            .chain(&self.coupled_substrates)
+            .chain(&self.coupled_products)
+    }
+
+    pub(crate) fn participants_mut(&mut self) -> impl Iterator<Item = &mut Participant> {
+        self.substrates
+            .iter_mut()
+            .chain(&mut self.products)
+            .chain(&mut self.coupled_substrates)
+            .chain(&mut self.coupled_products)
+    }
+
+    /// 両辺で元素ごとの原子の数と電荷が釣り合っているかを確かめ，
+    /// 釣り合わなければ diagnostics に警告を記録する．
+    /// 分子式の分からない化合物が 1 つでもあれば確かめない
+    pub(crate) fn check_balance(&self, items: &[Item], diagnostics: &mut Diagnostics) {
+        let left = match sum(
+            self.substrates.iter().chain(&self.coupled_substrates),
+            items,
+        ) {
+            Some(left) => left,
+            None => return,
+        };
+        let right = match sum(self.products.iter().chain(&self.coupled_products), items) {
+            Some(right) => right,
+            None => return,
+        };
+        let mut differences = Vec::new();
+        let symbols: BTreeSet<_> = left.atoms.keys().chain(right.atoms.keys()).collect();
+        for symbol in symbols {
+            let left = left.atoms.get(symbol).copied().unwrap_or(0);
+            let right = right.atoms.get(symbol).copied().unwrap_or(0);
+            if left != right {
+                differences.push(format!(
+                    "{}: {} on the left, {} on the right",
+                    symbol, left, right
+                ));
+            }
+        }
+        if left.charge != right.charge {
+            differences.push(format!(
+                "charge: {} on the left, {} on the right",
+                left.charge, right.charge
+            ));
+        }
+        if !differences.is_empty() {
+            diagnostics.warning(ReactionWarning::Unbalanced(self.span, differences));
+        }
+    }
+}
+
+// 係数を掛けて，化合物の分子式を足し合わせる．
+// \charge{ } が書かれていれば，分子式の電荷の代わりにそれを使う
+fn sum<'a>(participants: impl Iterator<Item = &'a Participant>, items: &[Item]) -> Option<Formula> {
+    let mut ret = Formula::default();
+    for participant in participants {
+        let item = &items[participant.item?];
+        let mut formula = item.formula.clone()?;
+        if let Some(charge) = item.declared_charge() {
+            formula.charge = charge;
+        }
+        ret.add(formula, participant.coefficient)?;
+    }
+    Some(ret)
+}
+
+// Token の列を行に分ける． Str の中の改行で区切り，
+// 行の前後の空白と，空白だけの行は除く
+fn lines(tokens: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut ret = vec![Vec::new()];
+    for token in tokens {
+        match token {
+            Token::Str(s, span) if s.contains('\n') => {
+                let mut start = 0;
+                for (i, part) in s.split('\n').enumerate() {
+                    if i != 0 {
+                        ret.push(Vec::new());
+                    }
+                    if !part.is_empty() {
+                        let span = span.sub(&s, start, part.len());
+                        ret.last_mut()
+                            .unwrap()
+                            .push(Token::Str(part.to_string(), span));
+                    }
+                    start += part.len() + 1;
+                }
+            }
+            token => ret.last_mut().unwrap().push(token),
+        }
+    }
+    for line in &mut ret {
+        if let Some(Token::Str(s, span)) = line.first_mut() {
+            let trimmed = s.trim_start();
+            *span = span.sub(s, s.len() - trimmed.len(), trimmed.len());
+            *s = trimmed.to_string();
+        }
+        if let Some(Token::Str(s, span)) = line.last_mut() {
+            let trimmed = s.trim_end();
+            *span = span.sub(s, 0, trimmed.len());
+            *s = trimmed.to_string();
+        }
+        line.retain(|token| !matches!(token, Token::Str(s, _) if s.is_empty()));
+    }
+    ret.retain(|line| !line.is_empty());
+    ret
+}
+
+// 行頭が "enzyme:" などなら，キーを取り除いて，キーとその位置を返す
+fn split_key(line: &mut Vec<Token>) -> Option<(&'static str, Span)> {
+    let (s, span) = match line.first_mut() {
+        Some(Token::Str(s, span)) => (s, span),
+        _ => return None,
+    };
+    for key in ["enzyme", "cofactor", "note"] {
+        let rest = match s
+            .strip_prefix(key)
+            .map(str::trim_start)
+            .and_then(|rest| rest.strip_prefix(':').or_else(|| rest.strip_prefix('：')))
+        {
+            Some(rest) => rest.trim_start(),
+            None => continue,
+        };
+        let key_span = span.sub(s, 0, key.len());
+        if rest.is_empty() {
+            line.remove(0);
+        } else {
+            *span = span.sub(s, s.len() - rest.len(), rest.len());
+            *s = rest.to_string();
+        }
+        return Some((key, key_span));
+    }
+    None
+}
+
+// 反応式を読み，左辺，右辺，可逆かどうかを返す
+fn parse_equation(
+    line: Vec<Token>,
+    line_span: Span,
+) -> Result<(Vec<Participant>, Vec<Participant>, bool), ReactionError> {
+    let mut substrates = Vec::new();
+    let mut products = Vec::new();
+    let mut arrow = None;
+    // 化合物の前に書かれた係数
+    let mut coefficient = None;
+    // 化合物を待っている + や矢印．化合物が来る前に他のものが来たら誤り
+    let mut pending = None;
+    for term in terms(line)? {
+        let side = if arrow.is_some() {
+            &mut products
+        } else {
+            &mut substrates
+        };
+        match term {
+            Term::Coefficient(n, span) => {
+                if coefficient.is_some() || (!side.is_empty() && pending.is_none()) {
+                    return Err(ReactionError::Unexpected(n.to_string(), span));
+                }
+                coefficient = Some((n, span));
+            }
+            Term::Compound(name) => {
+                if !side.is_empty() && pending.is_none() {
+                    return Err(ReactionError::Unexpected(format!("[{}]", name), name.span));
+                }
+                side.push(Participant {
+                    coefficient: coefficient.take().map_or(1, |(n, _)| n),
+                    name,
+                    item: None,
+                });
+                pending = None;
+            }
+            Term::Plus(span) => {
+                if side.is_empty() || pending.is_some() || coefficient.is_some() {
+                    return Err(ReactionError::Unexpected("+".to_string(), span));
+                }
+                pending = Some(("+", span));
+            }
+            Term::Arrow(reversible, s, span) => {
+                if arrow.is_some() {
+                    return Err(ReactionError::MultipleArrows(span));
+                }
+                if let Some((s, span)) = pending {
+                    return Err(ReactionError::Unexpected(s.to_string(), span));
+                }
+                if let Some((n, span)) = coefficient {
+                    return Err(ReactionError::Unexpected(n.to_string(), span));
+                }
+                if side.is_empty() {
+                    return Err(ReactionError::EmptySide("left", span));
+                }
+                arrow = Some((reversible, span));
+                pending = Some((s, span));
+            }
+        }
+    }
+    let (reversible, arrow_span) = arrow.ok_or(ReactionError::NoArrow(line_span))?;
+    if products.is_empty() {
+        return Err(ReactionError::EmptySide("right", arrow_span));
+    }
+    if let Some((n, span)) = coefficient {
+        return Err(ReactionError::Unexpected(n.to_string(), span));
+    }
+    if let Some((s, span)) = pending {
+        return Err(ReactionError::Unexpected(s.to_string(), span));
+    }
+    Ok((substrates, products, reversible))
+}
+
+fn terms(line: Vec<Token>) -> Result<Vec<Term>, ReactionError> {
+    let mut ret = Vec::new();
+    for token in line {
+        let (s, span) = match token {
+            Token::Link(text) => {
+                ret.push(Term::Compound(text));
+                continue;
+            }
+            Token::Str(s, span) => (s, span),
+            token => return Err(ReactionError::Unexpected(token.to_string(), token.span())),
+        };
+        let mut i = 0;
+        while let Some(c) = s[i..].chars().next() {
+            let rest = &s[i..];
+            let len = if c.is_whitespace() {
+                c.len_utf8()
+            } else if let Some(arrow) = IRREVERSIBLE.iter().find(|a| rest.starts_with(*a)) {
+                ret.push(Term::Arrow(false, arrow, span.sub(&s, i, arrow.len())));
+                arrow.len()
+            } else if let Some(arrow) = REVERSIBLE.iter().find(|a| rest.starts_with(*a)) {
+                ret.push(Term::Arrow(true, arrow, span.sub(&s, i, arrow.len())));
+                arrow.len()
+            } else if c == '+' {
+                ret.push(Term::Plus(span.sub(&s, i, 1)));
+                1
+            } else if c.is_ascii_digit() {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                let digits = &rest[..len];
+                let digits_span = span.sub(&s, i, len);
+                match digits.parse() {
+                    Ok(n) if n > 0 => ret.push(Term::Coefficient(n, digits_span)),
+                    _ => return Err(ReactionError::Unexpected(digits.to_string(), digits_span)),
+                }
+                len
+            } else {
+                return Err(ReactionError::Unexpected(
+                    c.to_string(),
+                    span.sub(&s, i, c.len_utf8()),
+                ));
+            };
+            i += len;
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util, Diagnostics, Document, Level};
+
+    const COMPOUNDS: &str = "
+        glucose [グルコース] \\formula{C_6H_{12}O_6}
+        g6p [グルコース 6-リン酸] \\formula{C_6H_{11}O_9P^{2-}}
+        atp [ATP] \\formula{C_{10}H_{12}N_5O_{13}P_3^{4-}}
+        adp [ADP] \\formula{C_{10}H_{12}N_5O_{10}P_2^{3-}}
+        proton [水素イオン] \\formula{H^+}
+        water [水] \\formula{H_2O}
+        hydrogen [水素] \\formula{H_2}
+        oxygen [酸素] \\formula{O_2}
+        chloride [塩化物イオン] \\formula{Cl} \\charge{-1}
+        sodium [ナトリウムイオン] \\formula{Na^+}
+        salt [塩化ナトリウム] \\formula{NaCl}
+        unknown [未知の化合物]
+    ";
+
+    // COMPOUNDS に続けて \reaction{ } に body を書いたものを読む
+    fn document(body: &str) -> (Document, Diagnostics) {
+        test_util::document(&format!("{}\\reaction{{\n{}\n}}", COMPOUNDS, body))
+    }
+
+    #[test]
+    fn parse_reaction() {
+        // (反応の中身, 左辺, 右辺, 可逆か)
+        type Case = (
+            &'static str,
+            &'static [(u64, &'static str)],
+            &'static [(u64, &'static str)],
+            bool,
+        );
+        let cases: &[Case] = &[
+            ("[水] -> [水素]", &[(1, "水")], &[(1, "水素")], false),
+            (
+                "[グルコース] + [ATP] → [グルコース 6-リン酸] + [ADP]",
+                &[(1, "グルコース"), (1, "ATP")],
+                &[(1, "グルコース 6-リン酸"), (1, "ADP")],
+                false,
+            ),
+            (
+                "2[水] → 2 [水素] + [酸素]",
+                &[(2, "水")],
+                &[(2, "水素"), (1, "酸素")],
+                false,
+            ),
+            ("[水] <=> [水素]", &[(1, "水")], &[(1, "水素")], true),
+            ("[水] ⇌ [水素]", &[(1, "水")], &[(1, "水素")], true),
+            (
+                "enzyme: [ATP]\n[水] ⇄ [水素]\nnote: 備考",
+                &[(1, "水")],
+                &[(1, "水素")],
+                true,
+            ),
+        ];
+        for &(body, substrates, products, reversible) in cases {
+            let (document, diagnostics) = document(body);
+            assert!(!diagnostics.has_errors(), "{}", body);
+            let reaction = &document.reactions[0];
+            let side = |participants: &[super::Participant]| -> Vec<(u64, String)> {
+                participants
+                    .iter()
+                    .map(|p| (p.coefficient, p.name.to_string()))
+                    .collect()
+            };
+            let expected = |side: &[(u64, &str)]| -> Vec<(u64, String)> {
+                side.iter()
+                    .map(|&(n, name)| (n, name.to_string()))
+                    .collect()
+            };
+            assert_eq!(side(&reaction.substrates), expected(substrates), "{}", body);
+            assert_eq!(side(&reaction.products), expected(products), "{}", body);
+            assert_eq!(reaction.reversible, reversible, "{}", body);
+            // 化合物は名前から探してある
+            assert!(
+                reaction.participants().all(|p| p.item.is_some()),
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn keys() {
+        let (document, diagnostics) = document(
+            "[グルコース] → [グルコース 6-リン酸]
+            enzyme: ヘキソキナーゼ
+            cofactor: [ATP] → [ADP] + [水素イオン]
+            cofactor: Mg^{2+}
+            note: 解糖系の最初の段階",
+        );
+        assert!(!diagnostics.has_errors());
+        let reaction = &document.reactions[0];
+        assert_eq!(
+            reaction.enzyme.as_ref().unwrap().to_string(),
+            "ヘキソキナーゼ"
+        );
+        assert_eq!(reaction.cofactors.len(), 2);
+        assert_eq!(reaction.coupled_substrates.len(), 1);
+        assert_eq!(reaction.coupled_products.len(), 2);
+        assert_eq!(
+            reaction.note.as_ref().unwrap().to_string(),
+            "解糖系の最初の段階"
+        );
+        // 関わる化合物の記事に反応が並ぶ
+        let atp = document
+            .items
+            .iter()
+            .find(|item| item.identity.value == "atp");
+        assert_eq!(atp.unwrap().reactions, [0]);
+    }
+
+    #[test]
+    fn invalid_reaction() {
+        let cases = [
+            "",
+            "enzyme: [ATP]",
+            "[水] + [水素]",
+            "[水] -> [水素] -> [酸素]",
+            "-> [水素]",
+            "[水] ->",
+            "[水] + -> [水素]",
+            "[水] + 水素 -> [酸素]",
+            "[水] -> [水素]\n[酸素] -> [水]",
+            "[水] -> [水素]\nenzyme: [ATP]\nenzyme: [ADP]",
+        ];
+        for body in cases {
+            let (document, diagnostics) = document(body);
+            assert!(document.reactions.is_empty(), "{:?}", body);
+            assert_eq!(diagnostics.error_count(), 1, "{:?}", body);
+        }
+    }
+
+    #[test]
+    fn balance() {
+        // (反応の中身, 警告の説明)
+        let cases: &[(&str, &[&str])] = &[
+            (
+                "[グルコース] + [ATP] → [グルコース 6-リン酸] + [ADP] + [水素イオン]",
+                &[],
+            ),
+            (
+                "[グルコース] + [ATP] → [グルコース 6-リン酸] + [ADP]",
+                &[
+                    "H: 24 on the left, 23 on the right",
+                    "charge: -4 on the left, -5 on the right",
+                ],
+            ),
+            (
+                "[グルコース] → [グルコース 6-リン酸] + [水素イオン]\ncofactor: [ATP] → [ADP]",
+                &[],
+            ),
+            ("2[水] → 2[水素] + [酸素]", &[]),
+            (
+                "[水] → [水素] + [酸素]",
+                &["O: 1 on the left, 2 on the right"],
+            ),
+            // \charge{ } が分子式の電荷の代わりになる
+            (
+                "[塩化物イオン] + [ナトリウムイオン] → [塩化ナトリウム]",
+                &[],
+            ),
+            (
+                "[塩化物イオン] → [塩化ナトリウム]",
+                &[
+                    "Na: 0 on the left, 1 on the right",
+                    "charge: -1 on the left, 0 on the right",
+                ],
+            ),
+            // 分子式の分からない化合物があれば確かめない
+            ("[未知の化合物] → [水]", &[]),
+            ("[存在しない化合物] → [水]", &[]),
+        ];
+        for &(body, expected) in cases {
+            let (_, diagnostics) = document(body);
+            assert!(!diagnostics.has_errors(), "{}", body);
+            let helps: Vec<&str> = diagnostics
+                .iter()
+                .filter(|(level, _)| *level == Level::Warning)
+                .flat_map(|(_, diagnostic)| diagnostic.helps())
+                .map(String::as_str)
+                .collect();
+            assert_eq!(helps, expected, "{}", body);
+        }
+    }
+}
@@ -1,35 +1,255 @@
-use std::collections::BTreeMap;
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::Path;
+use std::str::FromStr;
 
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use super::diagnostic::{Diagnostic, Diagnostics};
+use super::search_dir::{Dir, Entry};
+use super::source_map::{SourceMap, Span};
 
-use super::char::Char;
+/// 読み込んだ source の並び．
+/// サブディレクトリに入るところと出るところに，章の始まりと終わりを挟む．
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    File(usize), // source_map での番号
+    BeginChapter(String),
+    EndChapter,
+}
 
-pub fn read_files(paths: &BTreeMap<usize, PathBuf>) -> Result<Vec<Char>, Box<dyn Error>> {
-    let mut ret = Vec::new();
-    // paths のキーは，ファイル名先頭の番号
-    for (&i, path) in paths {
-        for (j, line) in BufReader::new(File::open(path)?).lines().enumerate() {
-            let mut count = 0usize;
-            for (k, c) in line?.chars().enumerate() {
-                ret.push(Char {
-                    value: c,
-                    file: i,
-                    line: j + 1,
-                    pos: k + 1,
-                });
-                count += 1;
+/// source のファイルの文字コード．
+/// Windows で書かれたファイルを，UTF-8 に変換せずに読めるようにする
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    ShiftJis,
+    EucJp,
+}
+
+impl Encoding {
+    fn get(self) -> &'static encoding_rs::Encoding {
+        match self {
+            Encoding::Utf8 => encoding_rs::UTF_8,
+            Encoding::ShiftJis => encoding_rs::SHIFT_JIS,
+            Encoding::EucJp => encoding_rs::EUC_JP,
+        }
+    }
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.get().name())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Encoding, String> {
+        match s.to_ascii_lowercase().replace('_', "-").as_str() {
+            "utf-8" | "utf8" => Ok(Encoding::Utf8),
+            "shift-jis" | "sjis" => Ok(Encoding::ShiftJis),
+            "euc-jp" => Ok(Encoding::EucJp),
+            _ => Err(format!(
+                "unknown encoding `{}` (expected `utf-8`, `shift_jis` or `euc-jp`)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum ReadError {
+    #[error("`{0}` is not valid {1} (invalid sequence at byte {2})")]
+    InvalidSequence(String, Encoding, usize, Span),
+}
+
+impl From<ReadError> for Diagnostic {
+    fn from(err: ReadError) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&err);
+        match err {
+            ReadError::InvalidSequence(_, encoding, _, span) => {
+                let diagnostic = diagnostic.label(span, "invalid sequence");
+                match encoding {
+                    Encoding::Utf8 => diagnostic.help(
+                        "save the file as UTF-8, or pass `--encoding shift_jis` or `--encoding euc-jp`",
+                    ),
+                    _ => diagnostic.help("check that `--encoding` matches the file"),
+                }
             }
-            // 改行は，その行の最後の文字とする
-            ret.push(Char {
-                value: '\n',
-                file: i,
-                line: j + 1,
-                pos: count + 1,
-            });
         }
     }
+}
+
+/// search_dir で見つけたファイルを番号順に読み込み， source_map に追加する．
+/// 返り値は，追加したファイルと章の区切りを読み込んだ順に並べたもの．
+/// 文字コードとして正しくないファイルは diagnostics に記録し，返り値には含めない．
+/// 続行できないエラー（ファイルが開けないなど）のときだけ Err を返す．
+pub fn read_files(
+    dir: &Dir,
+    encoding: Encoding,
+    source_map: &mut SourceMap,
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<Part>, Box<dyn Error>> {
+    let mut ret = Vec::new();
+    push_dir(dir, encoding, source_map, diagnostics, &mut ret)?;
     Ok(ret)
 }
+
+fn push_dir(
+    dir: &Dir,
+    encoding: Encoding,
+    source_map: &mut SourceMap,
+    diagnostics: &mut Diagnostics,
+    ret: &mut Vec<Part>,
+) -> Result<(), Box<dyn Error>> {
+    // entries のキーは，名前の先頭の番号
+    for entry in dir.entries.values() {
+        match entry {
+            Entry::File(path) => {
+                let bytes = std::fs::read(path)?;
+                if let Some(file) = decode(path, &bytes, encoding, source_map, diagnostics) {
+                    ret.push(Part::File(file));
+                }
+            }
+            Entry::Dir(dir) => {
+                ret.push(Part::BeginChapter(dir.title()));
+                push_dir(dir, encoding, source_map, diagnostics, ret)?;
+                ret.push(Part::EndChapter);
+            }
+        }
+    }
+    Ok(())
+}
+
+// bytes を encoding として読み， source_map に追加する．
+// 正しくない部分があったときは，その位置を示すために置換文字に置き換えて追加し，
+// エラーを記録して None を返す．
+pub(crate) fn decode(
+    path: &Path,
+    bytes: &[u8],
+    encoding: Encoding,
+    source_map: &mut SourceMap,
+    diagnostics: &mut Diagnostics,
+) -> Option<usize> {
+    let mut decoder = encoding.get().new_decoder_without_bom_handling();
+    let mut text = String::with_capacity(
+        decoder
+            .max_utf8_buffer_length_without_replacement(bytes.len())
+            .unwrap_or(bytes.len()),
+    );
+    let (result, read) = decoder.decode_to_string_without_replacement(bytes, &mut text, true);
+    match result {
+        encoding_rs::DecoderResult::InputEmpty => Some(source_map.add(path, prepare(&text))),
+        encoding_rs::DecoderResult::Malformed(len, after) => {
+            // text には，正しくない部分の直前までが入っている
+            let start = text.len();
+            let offset = read - after as usize - len as usize;
+            let (lossy, _) = encoding.get().decode_without_bom_handling(bytes);
+            let file = source_map.add(path, lossy.into_owned());
+            diagnostics.error(ReadError::InvalidSequence(
+                path.display().to_string(),
+                encoding,
+                offset,
+                Span {
+                    file,
+                    start,
+                    end: start + char::REPLACEMENT_CHARACTER.len_utf8(),
+                },
+            ));
+            None
+        }
+        // 容量は足りるように確保してある
+        encoding_rs::DecoderResult::OutputFull => unreachable!(),
+    }
+}
+
+// 先頭の BOM を取り除き，改行を \n に揃える．
+// パースするときの位置は，こうして揃えた後の文字列で数える
+fn prepare(text: &str) -> String {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// ファイルから読む代わりに，文字列を path という名前のファイルとして source_map に追加する．
+pub fn read_str<P: AsRef<Path>>(path: P, text: &str, source_map: &mut SourceMap) -> Part {
+    Part::File(source_map.add(path.as_ref(), prepare(text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, Encoding};
+    use crate::{Diagnostics, SourceMap};
+    use std::path::Path;
+
+    #[test]
+    fn prepare() {
+        // (読むバイト列, 文字コード, source_map に入る文字列)
+        let shift_jis = encoding_rs::SHIFT_JIS.encode("グルコース\r\n糖").0;
+        let euc_jp = encoding_rs::EUC_JP.encode("グルコース\n").0;
+        let cases: [(&[u8], Encoding, &str); 7] = [
+            (b"a\nb", Encoding::Utf8, "a\nb"),
+            ("\u{feff}a\n".as_bytes(), Encoding::Utf8, "a\n"),
+            (b"a\r\nb\r\n", Encoding::Utf8, "a\nb\n"),
+            (b"a\rb\r\r\nc", Encoding::Utf8, "a\nb\n\nc"),
+            ("\u{feff}あ\r\nい".as_bytes(), Encoding::Utf8, "あ\nい"),
+            (&shift_jis, Encoding::ShiftJis, "グルコース\n糖"),
+            (&euc_jp, Encoding::EucJp, "グルコース\n"),
+        ];
+        for (bytes, encoding, expected) in cases {
+            let mut source_map = SourceMap::default();
+            let mut diagnostics = Diagnostics::default();
+            let file = decode(
+                Path::new("test"),
+                bytes,
+                encoding,
+                &mut source_map,
+                &mut diagnostics,
+            );
+            assert_eq!(diagnostics.error_count(), 0, "{:?}", expected);
+            assert_eq!(source_map.file(file.unwrap()).text, expected);
+        }
+    }
+
+    #[test]
+    fn invalid_sequence() {
+        // (読むバイト列, 文字コード, 正しくない部分のバイト位置, 置換文字の位置)
+        let cases: [(&[u8], Encoding, usize, usize); 6] = [
+            (b"\xff", Encoding::Utf8, 0, 0),
+            (b"ab\xffcd", Encoding::Utf8, 2, 2),
+            // 途中で切れた「あ」（ e3 81 82 ）
+            (b"\xe3\x81\x82\xe3\x81", Encoding::Utf8, 3, 3),
+            // BOM を取り除く前の位置
+            (b"\xef\xbb\xbf\xff", Encoding::Utf8, 3, 3),
+            // 改行を揃える前の位置
+            (b"a\r\n\xff", Encoding::Utf8, 3, 3),
+            // Shift_JIS の「グル」（ 4 バイト）は UTF-8 では 6 バイト
+            (b"\x83O\x83\x8b\xff", Encoding::ShiftJis, 4, 6),
+        ];
+        for (bytes, encoding, offset, start) in cases {
+            let mut source_map = SourceMap::default();
+            let mut diagnostics = Diagnostics::default();
+            let file = decode(
+                Path::new("test"),
+                bytes,
+                encoding,
+                &mut source_map,
+                &mut diagnostics,
+            );
+            assert!(file.is_none(), "{:?}", bytes);
+            let errors: Vec<_> = diagnostics.iter().collect();
+            assert_eq!(errors.len(), 1, "{:?}", bytes);
+            let diagnostic = errors[0].1;
+            assert!(
+                diagnostic
+                    .message()
+                    .ends_with(&format!("(invalid sequence at byte {})", offset)),
+                "{}",
+                diagnostic.message()
+            );
+            let span = diagnostic.labels()[0].0;
+            assert_eq!((span.start, span.end), (start, start + 3), "{:?}", bytes);
+            // 置換文字を入れた文字列が source_map に入り，その位置を指す
+            let text = &source_map.file(span.file).text;
+            assert_eq!(&text[span.start..span.end], "\u{fffd}", "{:?}", bytes);
+        }
+    }
+}
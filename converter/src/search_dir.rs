@@ -1,3 +1,4 @@
+use super::ignore::{self, Patterns};
 use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs::read_dir;
@@ -5,47 +6,176 @@ use std::path::{Path, PathBuf};
 
 #[derive(thiserror::Error, Debug)]
 enum SearchDirError {
-    #[error("duplicate key (`{0}` and `{1}`)")]
-    DuplicateKey(PathBuf, PathBuf),
-    #[error("`{0}` is not a directory")]
-    NotADirectory(PathBuf),
+    #[error("`{1}` and `{2}` have the same number {0}; rename one of them")]
+    DuplicateNumber(usize, PathBuf, PathBuf),
+    #[error("the number at the start of `{0}` is too large")]
+    TooLargeNumber(PathBuf),
 }
 
-// str には AsRef<Path> が impl されているため，この関数は &str も受け取れる
-// 指定されたディレクトリ内のファイルを走査
-// ファイル名の先頭の番号を読んで，ファイルを数字の小さい順に並べる
-pub fn search_dir<P: AsRef<Path>>(path: P) -> Result<BTreeMap<usize, PathBuf>, Box<dyn Error>> {
-    let mut ret = BTreeMap::new();
+/// 走査したディレクトリ 1 つ分．
+/// 中のファイルとサブディレクトリを，名前の先頭の番号で並べたもの．
+#[derive(Debug)]
+pub struct Dir {
+    pub path: PathBuf,
+    pub entries: BTreeMap<usize, Entry>,
+    // 読まないことにしたもの．パスの順に並べる
+    pub ignored: Vec<Ignored>,
+}
+
+#[derive(Debug)]
+pub enum Entry {
+    File(PathBuf),
+    // サブディレクトリは章になる
+    Dir(Dir),
+}
+
+/// 読まないことにしたファイルやディレクトリと，その理由
+#[derive(Debug)]
+pub struct Ignored {
+    pub path: PathBuf,
+    pub reason: IgnoreReason,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum IgnoreReason {
+    #[error("hidden file")]
+    Hidden, // '.' で始まる（ .biochemignore 自身など）
+    #[error("name does not start with a number")]
+    NoNumber,
+    #[error("name is not valid UTF-8")]
+    InvalidName,
+    #[error("matches `{0}` in {}", ignore::FILE_NAME)]
+    Pattern(String), // .biochemignore のどの行にマッチしたか
+    #[error("neither a file nor a directory")]
+    NotAFile, // 壊れたシンボリックリンクなど
+}
+
+impl Dir {
+    /// 章の見出しにする名前．
+    /// ディレクトリ名から先頭の番号と，それに続く '_' を除いたもの
+    pub fn title(&self) -> String {
+        let name = self
+            .path
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        let title = name.trim_start_matches(|c: char| c.is_ascii_digit());
+        let title = title.strip_prefix('_').unwrap_or(title);
+        if title.is_empty() {
+            name
+        } else {
+            title.to_string()
+        }
+    }
+
+    /// サブディレクトリの中も含めて，全てのファイルを読む順に並べる
+    pub fn files(&self) -> Vec<&Path> {
+        let mut ret = Vec::new();
+        for entry in self.entries.values() {
+            match entry {
+                Entry::File(path) => ret.push(path.as_path()),
+                Entry::Dir(dir) => ret.extend(dir.files()),
+            }
+        }
+        ret
+    }
 
-    'entries: for entry in read_dir(path)? {
+    /// サブディレクトリの中も含めて，読まないことにしたものを全て集める
+    pub fn ignored_files(&self) -> Vec<&Ignored> {
+        let mut ret: Vec<_> = self.ignored.iter().collect();
+        for entry in self.entries.values() {
+            if let Entry::Dir(dir) = entry {
+                ret.extend(dir.ignored_files());
+            }
+        }
+        ret
+    }
+}
+
+impl Entry {
+    pub fn path(&self) -> &Path {
+        match self {
+            Entry::File(path) => path,
+            Entry::Dir(dir) => &dir.path,
+        }
+    }
+}
+
+/// str には `AsRef<Path>` が impl されているため，この関数は &str も受け取れる
+/// 指定されたディレクトリ内のファイルとサブディレクトリを走査
+/// 名前の先頭の番号を読んで，各階層ごとに数字の小さい順に並べる．
+/// 番号の無いものや .biochemignore のパターンにマッチしたものは，
+/// 理由とともに ignored に入れる
+pub fn search_dir<P: AsRef<Path>>(path: P) -> Result<Dir, Box<dyn Error>> {
+    let patterns = Patterns::load(path.as_ref())?;
+    search(path.as_ref(), "", &patterns)
+}
+
+// relative は source ディレクトリからの相対パス（区切りは / ，末尾に / を付ける）
+fn search(path: &Path, relative: &str, patterns: &Patterns) -> Result<Dir, Box<dyn Error>> {
+    let mut ret = Dir {
+        path: path.to_path_buf(),
+        entries: BTreeMap::new(),
+        ignored: Vec::new(),
+    };
+
+    for entry in read_dir(path)? {
         let path = entry?.path();
-        if path.is_file() {
-            let file_name = path.file_name().ok_or("")?.to_str().ok_or("")?;
-            let num = {
-                // 名前の先頭に数字が付いていないファイルは無視
-                let mut num = 0usize;
-                for (i, c) in file_name.char_indices() {
-                    match c.to_digit(10) {
-                        Some(d) => num = num * 10 + d as usize,
-                        None => if i == 0 {
-                            continue 'entries;
-                        } else {
-                            break;
-                        }
-                    }
-                }
-                num
-            };
-            if let Some(prev) = ret.remove(&num) {
-                return Err(Box::new(SearchDirError::DuplicateKey(prev, path)));
-            } else {
-                ret.insert(num, path);
+        let is_dir = path.is_dir();
+        let mut ignore = |reason| {
+            ret.ignored.push(Ignored {
+                path: path.clone(),
+                reason,
+            })
+        };
+        let file_name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) => file_name,
+            None => {
+                ignore(IgnoreReason::InvalidName);
+                continue;
             }
+        };
+        if file_name.starts_with('.') {
+            ignore(IgnoreReason::Hidden);
+            continue;
+        }
+        let relative = format!("{}{}", relative, file_name);
+        if let Some(pattern) = patterns.find(&relative, is_dir) {
+            ignore(IgnoreReason::Pattern(pattern.to_string()));
+            continue;
+        }
+        // 名前の先頭の数字
+        let digits = &file_name[..file_name
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(file_name.len())];
+        if digits.is_empty() {
+            ignore(IgnoreReason::NoNumber);
+            continue;
+        }
+        let num = match digits.parse() {
+            Ok(num) => num,
+            Err(_) => return Err(Box::new(SearchDirError::TooLargeNumber(path))),
+        };
+        let entry = if is_dir {
+            Entry::Dir(search(&path, &format!("{}/", relative), patterns)?)
+        } else if path.is_file() {
+            Entry::File(path)
         } else {
-            // 同じ番号のファイルが複数あると DuplicateKey エラー
-            return Err(Box::new(SearchDirError::NotADirectory(path)));
+            ignore(IgnoreReason::NotAFile);
+            continue;
+        };
+        // 同じ階層に同じ番号のものが複数あるとエラー
+        if let Some(prev) = ret.entries.get(&num) {
+            let mut paths = [prev.path().to_path_buf(), entry.path().to_path_buf()];
+            // read_dir の順序は決まっていないので，メッセージが毎回同じになるよう並べる
+            paths.sort();
+            let [first, second] = paths;
+            return Err(Box::new(SearchDirError::DuplicateNumber(
+                num, first, second,
+            )));
         }
+        ret.entries.insert(num, entry);
     }
+    ret.ignored.sort_by(|a, b| a.path.cmp(&b.path));
 
     Ok(ret)
 }
@@ -0,0 +1,210 @@
+use super::args::Options;
+use super::watch::watch;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// 変換に成功するたびに増える番号．
+// ブラウザは RELOAD_PATH を定期的に取得し，これが変わったら再読み込みする．
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+const RELOAD_PATH: &str = "/__reload";
+
+// 出力した HTML の </body> の直前に埋め込む．
+// GENERATION の部分には，その時点の GENERATION の値が入る．
+const RELOAD_SCRIPT: &str = "<script>(function () {\
+    var generation = \"GENERATION\";\
+    setInterval(function () {\
+        fetch(\"/__reload\").then(function (response) {\
+            return response.text();\
+        }).then(function (text) {\
+            if (text !== generation) location.reload();\
+        }).catch(function () {});\
+    }, 1000);\
+})();</script>";
+
+// 出力先のファイルがあるディレクトリを，address で配信する．
+// source ディレクトリ内のファイルが変わるたびに変換し直す．
+// 待ち受けを始められなかったときだけ返る．
+pub fn serve(address: &str, options: &Options) -> io::Error {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(err) => return err,
+    };
+    let (_, read) = super::build(options);
+    if !options.quiet {
+        eprintln!("serving at http://{}/", address);
+    }
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            watch(&options.source, read, || {
+                if !options.quiet {
+                    eprintln!("change detected, rebuilding");
+                }
+                let (succeeded, read) = super::build(options);
+                if succeeded {
+                    GENERATION.fetch_add(1, Ordering::SeqCst);
+                }
+                read
+            })
+        });
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    scope.spawn(move || {
+                        if let Err(err) = respond(stream, options) {
+                            if options.verbose {
+                                eprintln!("failed to respond: {}", err);
+                            }
+                        }
+                    });
+                }
+                Err(err) => {
+                    if options.verbose {
+                        eprintln!("failed to accept connection: {}", err);
+                    }
+                }
+            }
+        }
+    });
+    io::Error::other("server stopped")
+}
+
+fn respond(mut stream: TcpStream, options: &Options) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // ヘッダーは使わないので読み飛ばす
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let mut words = request.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return send(&mut stream, "400 Bad Request", "text/plain", b"bad request"),
+    };
+    if method != "GET" {
+        return send(
+            &mut stream,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"method not allowed",
+        );
+    }
+    let path = target.split('?').next().unwrap_or("/");
+    if path == RELOAD_PATH {
+        let generation = GENERATION.load(Ordering::SeqCst).to_string();
+        return send(&mut stream, "200 OK", "text/plain", generation.as_bytes());
+    }
+
+    let output = options.output_file();
+    let file = if path == "/" {
+        Some(output.clone())
+    } else {
+        let root = match output.parent() {
+            Some(root) if root != Path::new("") => root,
+            _ => Path::new("."),
+        };
+        resolve(root, path)
+    };
+    let body = match file.as_ref().map(std::fs::read) {
+        Some(Ok(body)) => body,
+        _ => return send(&mut stream, "404 Not Found", "text/plain", b"not found"),
+    };
+    let file = file.unwrap();
+    if same_file(&file, &output) {
+        let body = inject(&String::from_utf8_lossy(&body));
+        send(
+            &mut stream,
+            "200 OK",
+            "text/html; charset=utf-8",
+            body.as_bytes(),
+        )
+    } else {
+        send(&mut stream, "200 OK", content_type(&file), &body)
+    }
+}
+
+fn send(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+        Content-Type: {}\r\n\
+        Content-Length: {}\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: close\r\n\
+        \r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+// URL のパスを root 以下のファイルのパスにする．
+// root の外を指すもの（ .. を含むなど）は None
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut ret = root.to_path_buf();
+    for component in percent_decode(path).split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            c if c.contains('\\') => return None,
+            c => ret.push(c),
+        }
+    }
+    Some(ret)
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                ret.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        ret.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+fn same_file(left: &Path, right: &Path) -> bool {
+    match (left.canonicalize(), right.canonicalize()) {
+        (Ok(left), Ok(right)) => left == right,
+        _ => false,
+    }
+}
+
+fn inject(html: &str) -> String {
+    let script =
+        RELOAD_SCRIPT.replace("GENERATION", &GENERATION.load(Ordering::SeqCst).to_string());
+    match html.rfind("</body>") {
+        Some(index) => format!("{}{}{}", &html[..index], script, &html[index..]),
+        None => format!("{}{}", html, script),
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
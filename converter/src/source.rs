@@ -1,97 +1,160 @@
-use super::char::Char;
+use super::diagnostic::{Diagnostic, Diagnostics};
+use super::source_map::{SourceMap, Span};
 use super::text::Text;
 use super::text::Token;
+use serde::{Deserialize, Serialize};
 
-// パースのために使う．
+/// パースのために使う．
 pub struct Source<'a> {
-    source: &'a [Char],
-    iter: std::iter::Enumerate<std::slice::Iter<'a, Char>>,
+    source: &'a str,
+    file: usize, // SourceMap の何番目のファイル？
+    iter: std::str::CharIndices<'a>,
+    // いま開いている括弧に対応する閉じ括弧．
+    // 閉じ括弧が食い違っていたときに，どこまで戻ればよいかを判断するのに使う．
+    delims: Vec<char>,
 }
 
-pub enum Expr<'a> {
+/// ID やタグ名，分類名のような，括弧の外に書かれた語．
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Word {
+    pub value: String,
+    pub span: Span,
+}
+
+/// source の要素 1 つ分．
+/// 読み込んだ文字列を借用しないので，パースした後も自由に持ち回せる．
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Expr {
     // アルファベット，数字， '-' ， ',' で構成された文字列．
     // リンクの名前になる．
-    Identity(&'a [Char]),
+    Identity(Word),
     // 角括弧 [ ] で囲まれた部分．
-    Name(Text<'a>),
+    Name(Text),
     // たとえば \p{ 〜 } は
     // index.html の冒頭で
     // <p> 〜 </p> になる．
-    Head(&'a [Char], Text<'a>),
+    Head(Word, Text),
     // 説明文．
     // +解糖系{ グルコースは酸化されてピルビン酸になる }
     // の形式で書かれる．
-    Desc(&'a [Char], Text<'a>),
+    Desc(Word, Text),
+    // ここから章が始まる．
+    // source のサブディレクトリに対応し，ディレクトリ名から番号を除いたものが見出しになる．
+    BeginChapter(String),
+    // 章の終わり．
+    EndChapter,
 }
 
 #[derive(thiserror::Error, Debug)]
 enum ParseError {
-    #[error("unexpected character `{0}` at {0:b}")]
-    UnexpectedCharacter(Char),
-    #[error("no closing bracket to match `{0}` at {0:b}")]
-    NoClosingBracket(Char),
-    #[error("brackets `{0}` at {0:b} and `{1}` at {1:b} does not match")]
-    BracketsDoesNotMatch(Char, Char),
+    #[error("unexpected character `{0}`")]
+    UnexpectedCharacter(char, Span),
+    #[error("no closing bracket to match `{0}`")]
+    NoClosingBracket(char, Span),
+    #[error("brackets `{0}` and `{2}` do not match")]
+    BracketsDoesNotMatch(char, Span, char, Span),
+    #[error("raw HTML is not closed")]
+    UnclosedRaw(Span),
+    #[error("block comment is not closed")]
+    UnclosedComment(Span),
     #[error("unexpected end of file")]
-    UnexpectedEndOfFile,
+    UnexpectedEndOfFile(Span),
 }
 
-use std::error::Error;
+impl From<ParseError> for Diagnostic {
+    fn from(err: ParseError) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&err);
+        match err {
+            ParseError::UnexpectedCharacter(_, span) => diagnostic.label(span, "unexpected"),
+            ParseError::NoClosingBracket(_, span) => diagnostic.label(span, "not closed"),
+            ParseError::BracketsDoesNotMatch(_, open, _, close) => diagnostic
+                .label(close, "does not match")
+                .label(open, "opened here"),
+            ParseError::UnclosedRaw(span) => diagnostic.label(span, "raw HTML starts here"),
+            ParseError::UnclosedComment(span) => diagnostic
+                .label(span, "comment starts here")
+                .help("close it with `}%`"),
+            ParseError::UnexpectedEndOfFile(span) => {
+                diagnostic.label(span, "expected `{` after this")
+            }
+        }
+    }
+}
 
 impl<'a> Source<'a> {
-    // イテレータをもっておく．
-    // parse() から parse_block()
-    // parse_block() から parse_block()
-    // を呼び出したときに，
-    // イテレータを引数として渡す必要がない．
-    // （ある意味，グローバル変数のような使い方）
-    pub fn from(source: &'a [Char]) -> Source<'a> {
+    /// イテレータをもっておく．
+    /// parse() から parse_block()
+    /// parse_block() から parse_block()
+    /// を呼び出したときに，
+    /// イテレータを引数として渡す必要がない．
+    /// （ある意味，グローバル変数のような使い方）
+    /// file は source_map での番号で， Span に記録される．
+    pub fn from(source_map: &'a SourceMap, file: usize) -> Source<'a> {
+        let source = source_map.file(file).text.as_str();
         Source {
-            source: source,
-            iter: source.iter().enumerate(),
+            source,
+            file,
+            iter: source.char_indices(),
+            delims: Vec::new(),
         }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Expr<'a>>, Box<dyn Error>> {
+    /// エラーが見つかってもそこで止めずに，
+    /// diagnostics に記録して続きをパースする．
+    pub fn parse(&mut self, diagnostics: &mut Diagnostics) -> Vec<Expr> {
         let mut ret = Vec::new();
-        enum State<'a> {
+        enum State {
             Space,
             Identity(usize),
             Desc(usize),
             Head(usize),
-            Elem(Expr<'a>),
+            Elem(Expr),
         }
         let mut prev = State::Space;
         while let Some((i, c)) = self.iter.next() {
             let next = match prev {
-                State::Desc(index) => match c.value {
+                State::Desc(index) => match c {
                     '{' => State::Elem(Expr::Desc(
-                        &self.source[index + 1..i],
-                        self.parse_block(c, '}')?,
+                        self.tag(index, i),
+                        self.parse_block(i, c, '}', diagnostics),
                     )),
                     _ => continue,
                 },
-                State::Head(index) => match c.value {
+                State::Head(index) => match c {
                     '{' => State::Elem(Expr::Head(
-                        &self.source[index + 1..i],
-                        self.parse_block(c, '}')?,
+                        self.tag(index, i),
+                        self.parse_block(i, c, '}', diagnostics),
                     )),
                     _ => continue,
                 },
-                _ => match c.value {
+                _ => match c {
+                    '%' => {
+                        // コメントは空白と同じ扱い
+                        self.skip_comment(i, diagnostics);
+                        State::Space
+                    }
                     '+' => State::Desc(i),
                     '\\' => State::Head(i),
-                    '[' => State::Elem(Expr::Name(self.parse_block(c, ']')?)),
+                    '[' => State::Elem(Expr::Name(self.parse_block(i, c, ']', diagnostics))),
                     c if c.is_whitespace() => State::Space,
                     '-' | ',' => State::Identity(i),
                     c if c.is_alphanumeric() => State::Identity(i),
-                    _ => return Err(Box::new(ParseError::UnexpectedCharacter(c.clone()))),
+                    _ => {
+                        // 次の空白までを読み飛ばして，そこから次の要素としてパースし直す
+                        diagnostics.error(ParseError::UnexpectedCharacter(c, self.char_span(i, c)));
+                        for (_, c) in &mut self.iter {
+                            if c.is_whitespace() {
+                                break;
+                            }
+                        }
+                        State::Space
+                    }
                 },
             };
             match prev {
                 State::Identity(index) => match next {
                     State::Identity(_) => continue,
-                    _ => ret.push(Expr::Identity(&self.source[index..i])),
+                    _ => ret.push(Expr::Identity(self.word(index, i))),
                 },
                 State::Elem(elem) => ret.push(elem),
                 _ => {}
@@ -100,39 +163,327 @@ impl<'a> Source<'a> {
         }
         match prev {
             State::Space => {}
-            State::Identity(index) => ret.push(Expr::Identity(&self.source[index..])),
-            State::Desc(_) | State::Head(_) => {
-                return Err(Box::new(ParseError::UnexpectedEndOfFile))
+            State::Identity(index) => ret.push(Expr::Identity(self.word(index, self.source.len()))),
+            State::Desc(index) | State::Head(index) => {
+                diagnostics.error(ParseError::UnexpectedEndOfFile(self.span(index, index + 1)))
             }
             State::Elem(elem) => ret.push(elem),
         }
-        Ok(ret)
+        ret
     }
 
-    fn parse_block(&mut self, start: &Char, delim: char) -> Result<Text<'a>, Box<dyn Error>> {
-        let mut ret = Text { text: Vec::new() };
+    // start は開き括弧 open の位置
+    fn parse_block(
+        &mut self,
+        start: usize,
+        open: char,
+        delim: char,
+        diagnostics: &mut Diagnostics,
+    ) -> Text {
+        let open_span = self.char_span(start, open);
+        let mut ret = Text {
+            text: Vec::new(),
+            span: open_span,
+        };
         let mut escaped = false;
-        while let Some((_, c)) = self.iter.next() {
+        self.delims.push(delim);
+        loop {
+            // 閉じ括弧が食い違っていたときに読まずに戻れるよう，先読みする
+            let mut iter = self.iter.clone();
+            let (i, c) = match iter.next() {
+                Some(next) => next,
+                None => {
+                    diagnostics.error(ParseError::NoClosingBracket(open, open_span));
+                    break;
+                }
+            };
+            let span = self.char_span(i, c);
             if escaped {
-                ret.text.push(Token::EscapedChar(c));
+                self.iter = iter;
+                ret.text.push(Token::Escaped(c, span));
                 escaped = false;
-            } else {
-                match c.value {
-                    '\\' => escaped = true,
-                    '{' => ret.text.push(Token::Block(self.parse_block(c, '}')?)),
-                    '[' => ret.text.push(Token::Link(self.parse_block(c, ']')?)),
-                    '(' => ret.text.push(Token::Paren(self.parse_block(c, ')')?)),
-                    c if c == delim => return Ok(ret),
-                    '}' | ']' | ')' => {
-                        return Err(Box::new(ParseError::BracketsDoesNotMatch(
-                            start.clone(),
-                            c.clone(),
-                        )))
+                continue;
+            }
+            match c {
+                c if c == delim => {
+                    self.iter = iter;
+                    break;
+                }
+                '}' | ']' | ')' => {
+                    if self.delims.contains(&c) {
+                        // 外側の括弧を閉じるものだったら，
+                        // このブロックの閉じ括弧を書き忘れたとみなして，
+                        // 閉じ括弧を読まずに外側へ戻る
+                        diagnostics.error(ParseError::NoClosingBracket(open, open_span));
+                        break;
                     }
-                    _ => ret.text.push(Token::Char(c)),
+                    // そうでなければ，ただの文字として扱う
+                    diagnostics.error(ParseError::BracketsDoesNotMatch(open, open_span, c, span));
+                    self.iter = iter;
+                    push_char(&mut ret.text, c, span);
                 }
+                _ => {
+                    self.iter = iter;
+                    match c {
+                        '\\' => match self.iter.clone().next() {
+                            // \ の後に英字が続くときはマクロの呼び出し（ macros.rs で展開する）
+                            Some((_, c)) if c.is_ascii_alphabetic() => {
+                                let name = self.take_while(|c| c.is_ascii_alphabetic());
+                                let span = self.span(i, self.offset());
+                                ret.text.push(Token::Macro(name.to_string(), span));
+                            }
+                            _ => escaped = true,
+                        },
+                        '#' => match self.iter.clone().next() {
+                            // マクロの定義の中で，引数に置き換えられる
+                            Some((_, d @ '1'..='9')) => {
+                                self.iter.next();
+                                let span = self.span(i, self.offset());
+                                ret.text.push(Token::Param(d as usize - '0' as usize, span));
+                            }
+                            _ => push_char(&mut ret.text, c, span),
+                        },
+                        '{' => {
+                            ret.text
+                                .push(Token::Block(self.parse_block(i, c, '}', diagnostics)))
+                        }
+                        '[' => ret
+                            .text
+                            .push(Token::Link(self.parse_block(i, c, ']', diagnostics))),
+                        '(' => {
+                            ret.text
+                                .push(Token::Paren(self.parse_block(i, c, ')', diagnostics)))
+                        }
+                        '%' => self.skip_comment(i, diagnostics),
+                        '`' => {
+                            let raw = self.parse_raw(i, diagnostics);
+                            let span = self.span(i, self.offset());
+                            ret.text.push(Token::Raw(raw.to_string(), span));
+                        }
+                        '^' => ret.text.push(Token::Sup(span)),
+                        '_' => ret.text.push(Token::Sub(span)),
+                        _ => push_char(&mut ret.text, c, span),
+                    }
+                }
+            }
+        }
+        self.delims.pop();
+        // 開き括弧から，閉じ括弧（無ければ最後に読んだ文字）まで
+        ret.span = self.span(start, self.offset());
+        ret
+    }
+
+    // f を満たす文字が続く間読み進め，読んだ部分を返す
+    fn take_while<F: Fn(char) -> bool>(&mut self, f: F) -> &'a str {
+        let start = self.offset();
+        while let Some((_, c)) = self.iter.clone().next() {
+            if !f(c) {
+                break;
             }
+            self.iter.next();
+        }
+        &self.source[start..self.offset()]
+    }
+
+    // 次に読む文字の位置（読み終わっていればファイルの長さ）
+    fn offset(&self) -> usize {
+        self.iter.offset()
+    }
+
+    // start バイト目から end バイト目の手前まで
+    fn span(&self, start: usize, end: usize) -> Span {
+        Span {
+            file: self.file,
+            start,
+            end,
+        }
+    }
+
+    // start バイト目にある文字 c
+    fn char_span(&self, start: usize, c: char) -> Span {
+        self.span(start, start + c.len_utf8())
+    }
+
+    fn word(&self, start: usize, end: usize) -> Word {
+        Word {
+            value: self.source[start..end].to_string(),
+            span: self.span(start, end),
+        }
+    }
+
+    // start にある \ や + の直後から end の手前までを値とする．
+    // 位置は \ や + から含める
+    fn tag(&self, start: usize, end: usize) -> Word {
+        Word {
+            value: self.source[start + 1..end].to_string(),
+            span: self.span(start, end),
+        }
+    }
+
+    // % から行末までと， %{ から }% まではコメントとして読み飛ばす．
+    // 行末の改行は読まずに残す．
+    // start は % の位置
+    fn skip_comment(&mut self, start: usize, diagnostics: &mut Diagnostics) {
+        if self.source[self.offset()..].starts_with('{') {
+            match self.source[start..].find("}%") {
+                Some(end) => {
+                    let end = start + end + "}%".len();
+                    while self.offset() < end {
+                        self.iter.next();
+                    }
+                }
+                None => {
+                    diagnostics.error(ParseError::UnclosedComment(self.span(start, start + 2)));
+                    for _ in &mut self.iter {}
+                }
+            }
+        } else {
+            while let Some((_, c)) = self.iter.clone().next() {
+                if c == '\n' {
+                    break;
+                }
+                self.iter.next();
+            }
+        }
+    }
+
+    // ` から ` までは HTML としてそのまま出力する．
+    // 中ではエスケープも括弧も解釈しない．
+    // start は開きの ` の位置
+    fn parse_raw(&mut self, start: usize, diagnostics: &mut Diagnostics) -> &'a str {
+        let begin = self.offset();
+        for (i, c) in &mut self.iter {
+            if c == '`' {
+                return &self.source[begin..i];
+            }
+        }
+        diagnostics.error(ParseError::UnclosedRaw(self.span(start, start + 1)));
+        &self.source[begin..]
+    }
+}
+
+// 普通の文字は，直前の Token::Str につなげる．
+// ただし ^ や _ の直後の文字は，その 1 文字だけを修飾するので，
+// 1 文字で 1 つの Token::Str にしておく．
+fn push_char(text: &mut Vec<Token>, c: char, span: Span) {
+    let len = text.len();
+    let decorated = len >= 2 && matches!(text[len - 2], Token::Sup(_) | Token::Sub(_));
+    match text.last_mut() {
+        Some(Token::Str(s, prev)) if !decorated => {
+            s.push(c);
+            *prev = prev.to(span);
+        }
+        _ => text.push(Token::Str(c.to_string(), span)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Expr, Source};
+    use crate::{Diagnostics, SourceMap};
+
+    // s をパースし，結果と（エラーのメッセージ，最初のラベルの位置）を返す
+    fn parse(s: &str) -> (Vec<Expr>, Vec<(String, usize)>) {
+        let mut source_map = SourceMap::default();
+        let mut diagnostics = Diagnostics::default();
+        let file = source_map.add("test".as_ref(), s.to_string());
+        let exprs = Source::from(&source_map, file).parse(&mut diagnostics);
+        let errors = diagnostics
+            .iter()
+            .map(|(_, diagnostic)| {
+                let start = diagnostic.labels().first().map_or(0, |label| label.0.start);
+                (diagnostic.message().to_string(), start)
+            })
+            .collect();
+        (exprs, errors)
+    }
+
+    // 最後の説明の中身
+    fn last_desc(exprs: &[Expr]) -> String {
+        exprs
+            .iter()
+            .rev()
+            .find_map(|expr| match expr {
+                Expr::Desc(_, text) => Some(text.to_string()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn brackets() {
+        // (source, 最後の説明, エラー)．丸括弧は閉じていなくても ( ) の形で表示される
+        type Case = (&'static str, &'static str, &'static [(&'static str, usize)]);
+        let cases: &[Case] = &[
+            ("+{ a (b) [c] {d} }", " a (b) [c] {d} ", &[]),
+            (
+                "+{ x ( ] }",
+                " x ( ] )",
+                &[
+                    ("brackets `(` and `]` do not match", 7),
+                    ("no closing bracket to match `(`", 5),
+                ],
+            ),
+            (
+                "+{ a ( b }",
+                " a ( b )",
+                &[("no closing bracket to match `(`", 5)],
+            ),
+            (
+                "+{ a ) b }",
+                " a ) b ",
+                &[("brackets `{` and `)` do not match", 5)],
+            ),
+            ("+{ a \\) b }", " a \\) b ", &[]),
+        ];
+        for &(source, desc, expected) in cases {
+            let (exprs, errors) = parse(source);
+            assert_eq!(last_desc(&exprs), desc, "{}", source);
+            let expected: Vec<_> = expected.iter().map(|&(m, i)| (m.to_string(), i)).collect();
+            assert_eq!(errors, expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn tag_span() {
+        let (exprs, errors) = parse("\\formula{x} +解糖系{y}");
+        assert!(errors.is_empty());
+        let tags: Vec<_> = exprs
+            .iter()
+            .map(|expr| match expr {
+                Expr::Head(tag, _) | Expr::Desc(tag, _) => {
+                    (tag.value.as_str(), tag.span.start, tag.span.end)
+                }
+                _ => panic!(),
+            })
+            .collect();
+        // 位置は \ や + から
+        assert_eq!(tags, [("formula", 0, 8), ("解糖系", 12, 22)]);
+    }
+
+    #[test]
+    fn comments() {
+        // (source, 最後の説明, エラー)
+        type Case = (&'static str, &'static str, &'static [(&'static str, usize)]);
+        let cases: &[Case] = &[
+            // 行末までのコメント．改行は残る
+            ("% コメント\n+{a}", "a", &[]),
+            ("+{a} % +{b}\n", "a", &[]),
+            ("+{a % b }\n c}", "a \n c", &[]),
+            // % 以降は行末まで消えるので，文字として書くには \% とする
+            ("+{50% です\n}", "50\n", &[]),
+            ("+{50\\% です}", "50\\% です", &[]),
+            // 複数行のコメント
+            ("+{a %{ b\n c }% d}", "a  d", &[]),
+            ("%{ +{b} }% +{a}", "a", &[]),
+            ("+{a %{ } }% b}", "a  b", &[]),
+            ("+{a} %{ +{b}", "a", &[("block comment is not closed", 5)]),
+        ];
+        for &(source, desc, expected) in cases {
+            let (exprs, errors) = parse(source);
+            assert_eq!(last_desc(&exprs), desc, "{:?}", source);
+            let expected: Vec<_> = expected.iter().map(|&(m, i)| (m.to_string(), i)).collect();
+            assert_eq!(errors, expected, "{:?}", source);
         }
-        return Err(Box::new(ParseError::NoClosingBracket(start.clone())));
     }
 }
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// 読み込んだファイルの一覧．
+/// Span の file は，この中の何番目のファイルかを表す．
+/// パースするときも，エラーメッセージでファイル名と該当する行を出力するときも，
+/// ここにある文字列を使う．
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+/// 読み込んだファイル 1 つ分．
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub text: String,
+    // 各行の先頭が何バイト目か．
+    // 行番号と何文字目かは，必要になったときにここから求める
+    line_starts: Vec<usize>,
+}
+
+impl SourceMap {
+    /// ファイルを追加して，その番号を返す
+    pub fn add(&mut self, path: &Path, text: String) -> usize {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        self.files.push(SourceFile {
+            path: path.to_path_buf(),
+            text,
+            line_starts,
+        });
+        self.files.len() - 1
+    }
+    pub fn file(&self, file: usize) -> &SourceFile {
+        &self.files[file]
+    }
+    /// 読み込んだファイルのパス． \include で取り込んだものも含む
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|file| file.path.as_path())
+    }
+}
+
+impl SourceFile {
+    /// line 行目（ 1 始まり）の中身．改行は含まない
+    pub fn line(&self, line: usize) -> &str {
+        let start = match self.line_starts.get(line - 1) {
+            Some(&start) => start,
+            None => return "",
+        };
+        let end = self
+            .line_starts
+            .get(line)
+            .map_or(self.text.len(), |&next| next - 1);
+        self.text[start..end]
+            .strip_suffix('\r')
+            .unwrap_or(&self.text[start..end])
+    }
+    /// offset バイト目が何行目の何文字目か（どちらも 1 始まり）
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        let start = self.line_starts[line - 1];
+        (line, self.text[start..offset].chars().count() + 1)
+    }
+}
+
+/// ソース中の範囲．
+/// file 番目のファイルの start バイト目から end バイト目の手前まで．
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub file: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// self が文字列 s の位置のとき， s の start バイト目から len バイトの位置．
+    /// マクロを展開した結果などで self が s と対応していなければ， self 全体のまま
+    pub fn sub(self, s: &str, start: usize, len: usize) -> Span {
+        if self.end - self.start == s.len() {
+            Span {
+                file: self.file,
+                start: self.start + start,
+                end: self.start + start + len,
+            }
+        } else {
+            self
+        }
+    }
+
+    /// self の先頭から end の末尾までの範囲
+    pub fn to(self, end: Span) -> Span {
+        if self.file != end.file {
+            return self;
+        }
+        Span {
+            end: end.end,
+            ..self
+        }
+    }
+}
@@ -0,0 +1,80 @@
+// 見つからなかった名前に対して，よく似た候補を探す．
+// [グルコース6-リン酸] と グルコース 6-リン酸 のように
+// 空白の有無だけが違う場合を特に拾えるよう，
+// 空白を除いて小文字にしたものどうしの編集距離で比べる．
+
+// 提案する候補の最大数
+const MAX_SUGGESTIONS: usize = 3;
+
+// candidates は（比較する文字列，提案するもの）の組．
+// 似ているものから順に返す．
+pub fn suggest<T: PartialEq, I: IntoIterator<Item = (String, T)>>(
+    target: &str,
+    candidates: I,
+) -> Vec<T> {
+    let target = normalize(target);
+    // 長さの 1/3 程度までの違いなら似ているとみなす．
+    // 2 文字以下の名前は 1 文字違うだけで別物なので，正規化して一致するものだけ
+    let threshold = target.len() / 3;
+    let mut found: Vec<(usize, T)> = Vec::new();
+    for (key, value) in candidates {
+        let distance = distance(&target, &normalize(&key));
+        if distance > threshold {
+            continue;
+        }
+        match found.iter_mut().find(|(_, prev)| *prev == value) {
+            Some(prev) => prev.0 = prev.0.min(distance),
+            None => found.push((distance, value)),
+        }
+    }
+    found.sort_by_key(|&(distance, _)| distance);
+    found
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, value)| value)
+        .collect()
+}
+
+fn normalize(s: &str) -> Vec<char> {
+    super::normalize::normalize(s)
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// レーベンシュタイン距離
+fn distance(left: &[char], right: &[char]) -> usize {
+    let mut prev: Vec<usize> = (0..=right.len()).collect();
+    for (i, l) in left.iter().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, r) in right.iter().enumerate() {
+            let substitute = prev[j] + if l == r { 0 } else { 1 };
+            cur.push(substitute.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[right.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::suggest;
+
+    #[test]
+    fn short_names() {
+        let candidates = ["E", "A", "Ａ", "ATP", "ADP", "NAD", "グルコース 6-リン酸"];
+        let cases: &[(&str, &[&str])] = &[
+            ("e", &["E"]),
+            ("a", &["A", "Ａ"]),
+            ("b", &[]),
+            ("AT", &[]),
+            ("AMP", &["ATP", "ADP"]),
+            ("グルコース6-リン酸", &["グルコース 6-リン酸"]),
+        ];
+        for &(target, expected) in cases {
+            let found = suggest(target, candidates.iter().map(|&c| (c.to_string(), c)));
+            assert_eq!(found, expected, "{}", target);
+        }
+    }
+}
@@ -0,0 +1,19 @@
+// テストで使う，文字列から読み込む手順．
+
+use super::{parse, read_str, Diagnostics, Document, Encoding, Expr, SourceMap};
+
+// source を test という名前のファイルとして読み，パースしてマクロを展開する
+pub fn parse_str(source: &str) -> (Vec<Expr>, Diagnostics) {
+    let mut source_map = SourceMap::default();
+    let mut diagnostics = Diagnostics::default();
+    let file = read_str("test", source, &mut source_map);
+    let exprs = parse(&mut source_map, &[file], Encoding::Utf8, &mut diagnostics);
+    (exprs, diagnostics)
+}
+
+// parse_str に続けて Document にする
+pub fn document(source: &str) -> (Document, Diagnostics) {
+    let (exprs, mut diagnostics) = parse_str(source);
+    let document = Document::from_source(exprs, &mut diagnostics);
+    (document, diagnostics)
+}
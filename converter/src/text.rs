@@ -1,85 +1,159 @@
-use super::char::{Char, Display};
+use super::diagnostic::{Diagnostic, Diagnostics};
 use super::document::Document;
+use super::escape::{Fragment, Html};
+use super::source_map::Span;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 
 // { } で囲まれた部分が Text となる．
 // "\p{ }" のようなヘッダー部分と
 // "+解糖系{ グルコースが分解されてピルビン酸になる }" のような説明部分が該当する
 
-// Text は Token の列
-pub struct Text<'a> {
-    pub text: Vec<Token<'a>>,
+/// Text は Token の列
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Text {
+    pub text: Vec<Token>,
+    pub span: Span, // 両端の括弧を含めた位置
 }
 
-pub enum Token<'a> {
-    Char(&'a Char),        // 普通の文字
-    EscapedChar(&'a Char), // バックスラッシュでエスケープされた文字
-    Block(Text<'a>),       // 波括弧 { } で囲まれた部分．波括弧自体は出力されない
-    Link(Text<'a>),        // 角括弧 [ ] で囲まれた部分．ハイパーリンクになる
-    Paren(Text<'a>),       // 丸括弧 ( ) で囲まれた部分．丸括弧も含めて出力される
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Token {
+    Str(String, Span),   // 普通の文字の並び
+    Escaped(char, Span), // バックスラッシュでエスケープされた文字
+    Sup(Span),           // ^ ．直後の Token を上付きにする
+    Sub(Span),           // _ ．直後の Token を下付きにする
+    Block(Text),         // 波括弧 { } で囲まれた部分．波括弧自体は出力されない
+    Link(Text),          // 角括弧 [ ] で囲まれた部分．ハイパーリンクになる
+    Paren(Text),         // 丸括弧 ( ) で囲まれた部分．丸括弧も含めて出力される
+    Raw(String, Span),   // バッククォート ` ` で囲まれた部分．HTML としてそのまま出力される
+    Macro(String, Span), // \name ．マクロの呼び出し．パースの後で定義の中身に置き換えられる
+    Param(usize, Span),  // #1 ．マクロの定義の中で，引数に置き換えられる
 }
 
 // ^ （上付き）と _ （下付き）は，
 // 直後の Token 1 個を修飾する．
 // たとえば ^{〜} と書くとブロック全体が上付きになる．
+// ^ や _ の直後の 1 文字は，パースの時点で 1 文字だけの Token::Str にしておく．
 
 #[derive(thiserror::Error, Debug)]
 enum TextPrintError {
-    #[error("no text after `{0}` at {0:b}")]
-    NoDecorationTarget(Char), // ^ や _ の直後に何も無い場合
+    #[error("no text after `{0}`")]
+    NoDecorationTarget(char, Span), // ^ や _ の直後に何も無い場合
 }
 
-enum Decoration<'a> {
-    Sup(&'a Char),
-    Sub(&'a Char),
+impl From<TextPrintError> for Diagnostic {
+    fn from(err: TextPrintError) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&err);
+        match err {
+            TextPrintError::NoDecorationTarget(_, span) => {
+                diagnostic.label(span, "expected text after this")
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum TextPrintWarning {
+    #[error("'{0}' not found")]
+    LinkNotFound(String, Span, Vec<String>), // [ ] の中身の名前をもつ化合物が無い場合．似た名前の候補も付ける
+    #[error("`{0}` matches `{2}` only after normalization")]
+    LinkNormalized(String, Span, String), // 正規化してはじめて一致した場合
 }
 
-impl<'a> Text<'a> {
-    // Text を index.html に出力するときに使う．
-    // [ ] をリンクにするために，引数で受け取った Document を参照する．
+impl From<TextPrintWarning> for Diagnostic {
+    fn from(warning: TextPrintWarning) -> Diagnostic {
+        let diagnostic = Diagnostic::new(&warning);
+        match warning {
+            TextPrintWarning::LinkNotFound(_, span, suggestions) => {
+                let diagnostic = diagnostic.label(span, "no item has this name");
+                let suggestions: Vec<_> = suggestions
+                    .iter()
+                    .map(|name| format!("`[{}]`", name))
+                    .collect();
+                match suggestions.len() {
+                    0 => diagnostic,
+                    1 => diagnostic.help(format!("did you mean {}?", suggestions[0])),
+                    _ => {
+                        diagnostic.help(format!("did you mean one of {}?", suggestions.join(", ")))
+                    }
+                }
+            }
+            TextPrintWarning::LinkNormalized(_, span, name) => diagnostic
+                .label(span, "differs in whitespace or character width")
+                .help(format!("write `[{}]` instead", name)),
+        }
+    }
+}
+
+enum Decoration {
+    Sup(Span),
+    Sub(Span),
+}
+
+impl Text {
+    /// Text を index.html に出力するときに使う．
+    /// [ ] をリンクにするために，引数で受け取った Document を参照する．
+    /// 返すエラーは書き込みの失敗のみで，
+    /// 記述の誤りは diagnostics に記録して出力を続ける．
     pub fn print<Writer: std::io::Write>(
         &self,
         writer: &mut Writer,
         document: &Document,
+        diagnostics: &mut Diagnostics,
     ) -> Result<(), Box<dyn Error>> {
         let mut decorations = Vec::new();
         for token in &self.text {
             match token {
-                Token::Char(c) => match c.value {
-                    '^' => {
-                        write!(writer, "<sup>")?;
-                        decorations.push(Decoration::Sup(c));
-                        continue;
-                    }
-                    '_' => {
-                        write!(writer, "<sub>")?;
-                        decorations.push(Decoration::Sub(c));
-                        continue;
-                    }
-                    _ => {
-                        write!(writer, "{}", c)?;
-                    }
-                },
-                Token::EscapedChar(c) => {
-                    write!(writer, "{}", c)?;
+                Token::Sup(span) => {
+                    write!(writer, "<sup>")?;
+                    decorations.push(Decoration::Sup(*span));
+                    continue;
+                }
+                Token::Sub(span) => {
+                    write!(writer, "<sub>")?;
+                    decorations.push(Decoration::Sub(*span));
+                    continue;
+                }
+                Token::Str(s, _) => {
+                    write!(writer, "{}", Html(s))?;
+                }
+                Token::Escaped(c, _) => {
+                    write!(writer, "{}", Html(c))?;
+                }
+                Token::Raw(s, _) => {
+                    write!(writer, "{}", s)?;
+                }
+                // 展開されずに残ったもの（未定義のマクロなど．エラーは展開のときに記録済み）は，
+                // 書かれたままの形で出力する
+                Token::Macro(..) | Token::Param(..) => {
+                    write!(writer, "{}", Html(token))?;
                 }
                 Token::Block(text) => {
-                    text.print(writer, document)?;
+                    text.print(writer, document, diagnostics)?;
                 }
                 Token::Paren(text) => {
                     write!(writer, "(")?;
-                    text.print(writer, document)?;
+                    text.print(writer, document, diagnostics)?;
                     write!(writer, ")")?;
                 }
                 Token::Link(text) => {
-                    match document.names.get(&text) {
-                        Some(&index) => {
+                    match document.find(text) {
+                        Some((index, exact)) => {
+                            if !exact {
+                                // 空白や全角/半角の違いを無視すれば一致するとき：
+                                // リンクにはするが，書き方を揃えるよう警告する
+                                diagnostics.warning(TextPrintWarning::LinkNormalized(
+                                    text.to_string(),
+                                    text.span,
+                                    document.items[index].name.as_ref().unwrap().to_string(),
+                                ));
+                            }
                             write!(
                                 writer,
                                 "<a href=\"#{}\">",
-                                Display::from(document.items[index].identity)
+                                Fragment(&document.items[index].identity.value)
                             )?;
-                            text.print(writer, document)?;
+                            text.print(writer, document, diagnostics)?;
                             write!(writer, "</a>")?;
                         }
                         None => {
@@ -88,11 +162,15 @@ impl<'a> Text<'a> {
                             // 警告を出した上で，
                             // リンクにする代わりに <span class="no_link"> </span> で囲む．
                             // "no_link" は style.css で赤文字などにする．
-                            eprint!("Warning: '");
-                            text.print(&mut std::io::BufWriter::new(std::io::stderr()), document)?;
-                            eprintln!("' not found");
+                            let name = text.to_string();
+                            let suggestions = document.suggest(&name);
+                            diagnostics.warning(TextPrintWarning::LinkNotFound(
+                                name,
+                                text.span,
+                                suggestions,
+                            ));
                             write!(writer, "<span class=\"no_link\">")?;
-                            text.print(writer, document)?;
+                            text.print(writer, document, diagnostics)?;
                             write!(writer, "</span>")?;
                         }
                     }
@@ -114,67 +192,67 @@ impl<'a> Text<'a> {
         }
         // ここで decoration の中身が残っていたら，
         // Text の最後に ^ か _ があったということ
-        match decorations.first() {
-            Some(Decoration::Sup(c)) | Some(Decoration::Sub(c)) => {
-                Err(Box::new(TextPrintError::NoDecorationTarget((*c).clone())))
+        // 開いたままの <sup> や <sub> は閉じておく
+        for decoration in decorations.iter().rev() {
+            match decoration {
+                Decoration::Sup(_) => write!(writer, "</sup>")?,
+                Decoration::Sub(_) => write!(writer, "</sub>")?,
             }
-            None => Ok(()),
         }
-    }
-}
-
-// document.rs で HashMap のキーにするので
-// Eq と Hash を impl
-
-impl<'a> PartialEq for Text<'a> {
-    fn eq(&self, other: &Self) -> bool {
-        if self.text.len() == other.text.len() {
-            for (left, right) in self.text.iter().zip(other.text.iter()) {
-                if left != right {
-                    return false;
-                }
+        match decorations.first() {
+            Some(Decoration::Sup(span)) => {
+                diagnostics.error(TextPrintError::NoDecorationTarget('^', *span))
+            }
+            Some(Decoration::Sub(span)) => {
+                diagnostics.error(TextPrintError::NoDecorationTarget('_', *span))
             }
-            true
-        } else {
-            false
+            None => {}
         }
+        Ok(())
     }
 }
-impl<'a> Eq for Text<'a> {}
 
-impl<'a> PartialEq for Token<'a> {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Token::Char(left), Token::Char(right))
-            | (Token::EscapedChar(left), Token::EscapedChar(right)) => left == right,
-            (Token::Block(left), Token::Block(right))
-            | (Token::Link(left), Token::Link(right))
-            | (Token::Paren(left), Token::Paren(right)) => left == right,
-            _ => false,
+impl Token {
+    /// source 上の位置． { } などでくくられたものは括弧を含む
+    pub fn span(&self) -> Span {
+        match self {
+            Token::Str(_, span)
+            | Token::Escaped(_, span)
+            | Token::Sup(span)
+            | Token::Sub(span)
+            | Token::Raw(_, span)
+            | Token::Macro(_, span)
+            | Token::Param(_, span) => *span,
+            Token::Block(text) | Token::Link(text) | Token::Paren(text) => text.span,
         }
     }
 }
-impl<'a> Eq for Token<'a> {}
-
-use std::hash;
 
-impl<'a> hash::Hash for Text<'a> {
-    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+// エラーメッセージなどで Text を出力したいとき，
+// source に書かれていたのと同じ形で出力する．
+// document.rs では，これを名前の HashMap のキーにもする．
+impl std::fmt::Display for Text {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         for token in &self.text {
-            token.hash(state);
+            write!(f, "{}", token)?;
         }
+        Ok(())
     }
 }
 
-impl<'a> hash::Hash for Token<'a> {
-    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Token::Char(c) | Token::EscapedChar(c) => {
-                c.hash(state);
-            }
-            Token::Block(text) | Token::Link(text) | Token::Paren(text) => {
-                text.hash(state);
-            }
+            Token::Str(s, _) => write!(f, "{}", s),
+            Token::Escaped(c, _) => write!(f, "\\{}", c),
+            Token::Sup(_) => write!(f, "^"),
+            Token::Sub(_) => write!(f, "_"),
+            Token::Block(text) => write!(f, "{{{}}}", text),
+            Token::Link(text) => write!(f, "[{}]", text),
+            Token::Paren(text) => write!(f, "({})", text),
+            Token::Raw(s, _) => write!(f, "`{}`", s),
+            Token::Macro(name, _) => write!(f, "\\{}", name),
+            Token::Param(n, _) => write!(f, "#{}", n),
         }
     }
 }
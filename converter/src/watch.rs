@@ -0,0 +1,57 @@
+use converter::search_dir;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// 更新を調べる間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// source ディレクトリ内の（サブディレクトリも含めた）ファイルと，その最終更新時刻．
+// 番号が無いなどで読まないファイルも含める．
+// さらに，前回の変換で読み込んだファイル（ \include で取り込んだものも含む）を加える．
+// 読まないディレクトリの中のファイルは，こうして \include されたものだけが対象になる．
+// ファイルの追加や削除，更新があると値が変わる．
+// 走査に失敗したときはそのエラーメッセージ（直ったときに変化として検出するため）．
+type Snapshot = Result<BTreeMap<PathBuf, Option<SystemTime>>, String>;
+
+fn snapshot(dir: &Path, read: &[PathBuf]) -> Snapshot {
+    let dir = search_dir(dir).map_err(|err| err.to_string())?;
+    let ignored = dir.ignored_files();
+    Ok(dir
+        .files()
+        .into_iter()
+        .chain(ignored.iter().map(|ignored| ignored.path.as_path()))
+        .chain(read.iter().map(PathBuf::as_path))
+        .map(|path| (path.to_path_buf(), modified(path)))
+        .collect())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|meta| meta.modified()).ok()
+}
+
+// dir 内のファイルを定期的に調べ，変化があるたびに rebuild を呼ぶ．
+// read と rebuild の返り値は，直前の変換で読み込んだファイル．
+// 終了しない（ Ctrl-C で止める）．
+pub fn watch<F: FnMut() -> Vec<PathBuf>>(dir: &Path, mut read: Vec<PathBuf>, mut rebuild: F) -> ! {
+    let mut prev = snapshot(dir, &read);
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        let next = snapshot(dir, &read);
+        if next != prev {
+            read = rebuild();
+            // \include するファイルが増減したかもしれないので，変換した後に読むファイルを基準にする．
+            // 変換する前からあったファイルは，変換中の更新も拾えるよう変換前の時刻にしておく
+            prev = match (snapshot(dir, &read), next) {
+                (Ok(current), Ok(next)) => Ok(current
+                    .into_iter()
+                    .map(|(path, modified)| {
+                        let modified = next.get(&path).copied().unwrap_or(modified);
+                        (path, modified)
+                    })
+                    .collect()),
+                (current, _) => current,
+            };
+        }
+    }
+}